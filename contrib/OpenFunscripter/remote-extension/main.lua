@@ -20,7 +20,7 @@ filterNames = {
     "VR-3D-SBS-180"
 }
 filterValues = {
-    "v360=input=he:in_stereo=sbs:pitch={pitch}:yaw={yaw}:roll=0:output=flat:d_fov={fov}:w=512:h=512"
+    "v360=input=he:in_stereo=sbs:pitch={pitch}:yaw={yaw}:roll={roll}:output=flat:d_fov={fov}:w=512:h=512"
 }
 
 function exists(file)
@@ -103,6 +103,9 @@ function binding.start_funscript_generator()
         table.insert(args, tostring(math.floor(next_action.at*1000.0)))
     end
 
+    -- a failed run must not leave the result of the previous run for the import
+    os.remove(tmpFile)
+
     print("cmd: ", cmd)
     print("args: ", table.unpack(args))
 
@@ -14,7 +14,7 @@
         release-version = "0.0.3"; # must match version in Cargo.toml
         release-rev = "0866d97e0baed68078059488cee007d7e4226479";
         git = "https://github.com/michael-mueller-git/mtfg-rs";
-        rust-version = "1.65.0";
+        rust-version = "1.73.0";
 
         cargoToml = (builtins.fromTOML (builtins.readFile ./Cargo.toml));
         cargo-name = "${cargoToml.package.name}";
@@ -1,12 +1,29 @@
+use crate::benchmark::BenchmarkArgs;
+use crate::evaluate::EvaluateArgs;
+use crate::filter::SmoothingMethod;
+use crate::funscript::OutputFormat;
+use crate::interpolate::InterpolationMethod;
+use crate::scene_cut::SceneCutAction;
+use crate::simplify::SimplifyMethod;
+use crate::tracker::TrackerKind;
+use crate::trajectories::Detrend;
+use crate::trajectories::Normalization;
 use clap::Parser;
 use log::error;
 
+/// The trackers hold back the boxes of a forward-backward window, the window must fit into
+/// the frame queue of the preview
+const MAX_FB_WINDOW: usize = 32;
+
+pub const DEFAULT_VIDEO_FILTER: &str = "v360=input=he:in_stereo=sbs:pitch={pitch}:yaw={yaw}:roll={roll}:output=flat:d_fov={fov}:w=800:h=800";
+
 #[derive(Parser)]
 #[clap(
     name = "mtfg-rs",
     about = "Motion Tracking Funscript Generator",
     version,
-    author
+    author,
+    after_help = "Use 'mtfg-rs evaluate --help' to compare a funscript with a reference script and 'mtfg-rs benchmark --help' to measure the pipeline speed"
 )]
 pub struct Args {
     /// Path to Video File
@@ -38,15 +55,174 @@ pub struct Args {
     pub preview_frames: u32,
 
     /// Video Filter with output 'w=\d:h=\d' parameter
-    #[clap(
-        long = "filter",
-        default_value = "v360=input=he:in_stereo=sbs:pitch={pitch}:yaw={yaw}:roll=0:output=flat:d_fov={fov}:w=800:h=800"
-    )]
+    #[clap(long = "filter", default_value = DEFAULT_VIDEO_FILTER)]
     pub video_filter: String,
 
+    /// Track without preview windows, requires the ROIs from `--roi` or `--propose-roi`
+    #[clap(long = "headless")]
+    pub headless: bool,
+
+    /// ROI 'x,y,width,height' in the filtered frame, once per person
+    #[clap(long = "roi", value_parser = parse_rect)]
+    pub roi: Vec<opencv::core::Rect>,
+
+    /// Propose the ROIs with the strongest periodic vertical motion, used directly in
+    /// headless mode. With `--persons 2` the proposals are assigned to the persons from top
+    /// to bottom
+    #[clap(long = "propose-roi")]
+    pub propose_roi: bool,
+
+    /// Length in seconds of the motion analysis for the ROI proposal
+    #[clap(long = "propose-seconds", default_value = "3.0")]
+    pub propose_seconds: f32,
+
+    /// Tracker that follows each ROI, optical-flow integrates the mean vertical and horizontal
+    /// motion inside the ROI instead of matching its appearance
+    #[clap(long = "tracker", value_enum, default_value = "csrt")]
+    pub tracker: TrackerKind,
+
+    /// Split the segment into chunks that are tracked in parallel, requires `--headless`
+    #[clap(long = "chunks", default_value = "1")]
+    pub chunks: usize,
+
+    /// Track each window of frames backward and drop the frames where the forward and
+    /// backward boxes of any feature diverge, doubles the tracking time
+    #[clap(long = "fb-check")]
+    pub fb_check: bool,
+
+    /// Number of frames per backward tracking of the forward-backward check
+    #[clap(long = "fb-window", default_value = "16")]
+    pub fb_window: usize,
+
+    /// Maximum distance of the forward and backward box center relative to the box size
+    #[clap(long = "fb-threshold", default_value = "0.25")]
+    pub fb_threshold: f64,
+
+    /// Action on a detected scene cut, the cuts are stored as bookmarks
+    #[clap(long = "scene-cut", value_enum, default_value = "none")]
+    pub scene_cut: SceneCutAction,
+
+    /// Color histogram difference (0 to 1) of consecutive frames that counts as scene cut
+    #[clap(long = "scene-cut-threshold", default_value = "0.4")]
+    pub scene_cut_threshold: f64,
+
+    /// Log the time spent in each pipeline stage at the end
+    #[clap(long = "stats")]
+    pub stats: bool,
+
     /// epsilon value for Ramer–Douglas–Peucker algorithm
     #[clap(long = "epsilon")]
     pub epsilon: f64,
+
+    /// Filter for the tracking box trajectories
+    #[clap(long = "smoothing", value_enum, default_value = "none")]
+    pub smoothing: SmoothingMethod,
+
+    /// Number of samples for the moving average and Savitzky–Golay smoothing
+    #[clap(long = "smoothing-window", default_value = "5")]
+    pub smoothing_window: usize,
+
+    /// Process noise of the Kalman smoothing, higher values follow faster movements
+    #[clap(long = "kalman-process-noise", default_value = "100000.0")]
+    pub kalman_process_noise: f64,
+
+    /// Minimum cutoff frequency in Hz of the one euro smoothing
+    #[clap(long = "one-euro-min-cutoff", default_value = "1.0")]
+    pub one_euro_min_cutoff: f64,
+
+    /// Speed coefficient of the one euro smoothing
+    #[clap(long = "one-euro-beta", default_value = "0.05")]
+    pub one_euro_beta: f64,
+
+    /// Removal of slow position changes before the normalization
+    #[clap(long = "detrend", value_enum, default_value = "none")]
+    pub detrend: Detrend,
+
+    /// Length in seconds of the slowest movement kept by the high-pass and baseline detrend
+    #[clap(long = "detrend-window", default_value = "10.0")]
+    pub detrend_window: f64,
+
+    /// Polynomial degree for the polynomial detrend
+    #[clap(long = "detrend-degree", default_value = "2")]
+    pub detrend_degree: usize,
+
+    /// Normalization of the stroke amplitude
+    #[clap(long = "normalize", value_enum, default_value = "global")]
+    pub normalize: Normalization,
+
+    /// Sliding window length in seconds for the window normalization
+    #[clap(long = "normalize-window", default_value = "4.0")]
+    pub normalize_window: f64,
+
+    /// Percentage of outliers ignored on each side by the normalization
+    #[clap(long = "clip-percentile", default_value = "0.0")]
+    pub clip_percentile: f64,
+
+    /// Lowest position of the generated script
+    #[clap(long = "min-position", default_value = "0")]
+    pub min_position: u8,
+
+    /// Highest position of the generated script
+    #[clap(long = "max-position", default_value = "100")]
+    pub max_position: u8,
+
+    /// Invert the score (higher box = lower position), the positions are written inverted
+    /// so the funscript `inverted` flag stays false
+    #[clap(long = "invert")]
+    pub invert: bool,
+
+    /// Time offset in milliseconds added to all actions (latency compensation)
+    #[clap(long = "offset", default_value = "0.0", allow_negative_numbers = true)]
+    pub offset: f32,
+
+    /// Output format, by default selected by the output file extension: `.csv` for csv,
+    /// `.raw.csv` for raw-csv, `.ofp` for ofp and funscript otherwise
+    #[clap(long = "format", value_enum)]
+    pub format: Option<OutputFormat>,
+
+    /// Write indented funscript JSON
+    #[clap(long = "pretty")]
+    pub pretty: bool,
+
+    /// Keep the previous output file as `<output>.bak`
+    #[clap(long = "backup")]
+    pub backup: bool,
+
+    /// Insert the actions into an existing output file instead of overwriting it
+    #[clap(long = "merge")]
+    pub merge: bool,
+
+    /// Reduction of the action points
+    #[clap(long = "simplify", value_enum, default_value = "rdp")]
+    pub simplify: SimplifyMethod,
+
+    /// Minimum stroke height for the peaks simplification
+    #[clap(long = "prominence", default_value = "10.0")]
+    pub prominence: f64,
+
+    /// Minimum triangle area (frames × position) for the Visvalingam–Whittaker simplification
+    #[clap(long = "min-area", default_value = "10.0")]
+    pub min_area: f64,
+
+    /// Maximum number of actions in the generated script
+    #[clap(long = "max-actions")]
+    pub max_actions: Option<usize>,
+
+    /// Maximum number of actions within any window of one second
+    #[clap(long = "max-actions-per-second")]
+    pub max_actions_per_second: Option<f64>,
+
+    /// Maximum device speed in position units per second
+    #[clap(long = "max-speed")]
+    pub max_speed: Option<f64>,
+
+    /// Minimum time between two actions in milliseconds
+    #[clap(long = "min-interval")]
+    pub min_interval: Option<f64>,
+
+    /// Interpolation method for the frames skipped by the frame step size
+    #[clap(long = "interpolation", value_enum, default_value = "monotonic-cubic")]
+    pub interpolation: InterpolationMethod,
 }
 
 impl Clone for Args {
@@ -59,12 +235,89 @@ impl Clone for Args {
             frame_step_size: self.frame_step_size,
             preview_frames: self.preview_frames,
             video_filter: self.video_filter.clone(),
+            headless: self.headless,
+            tracker: self.tracker,
+            chunks: self.chunks,
+            fb_check: self.fb_check,
+            fb_window: self.fb_window,
+            fb_threshold: self.fb_threshold,
+            scene_cut: self.scene_cut,
+            scene_cut_threshold: self.scene_cut_threshold,
+            stats: self.stats,
+            roi: self.roi.clone(),
+            propose_roi: self.propose_roi,
+            propose_seconds: self.propose_seconds,
             persons: self.persons,
             epsilon: self.epsilon,
+            smoothing: self.smoothing,
+            smoothing_window: self.smoothing_window,
+            kalman_process_noise: self.kalman_process_noise,
+            one_euro_min_cutoff: self.one_euro_min_cutoff,
+            one_euro_beta: self.one_euro_beta,
+            detrend: self.detrend,
+            detrend_window: self.detrend_window,
+            detrend_degree: self.detrend_degree,
+            normalize: self.normalize,
+            normalize_window: self.normalize_window,
+            clip_percentile: self.clip_percentile,
+            min_position: self.min_position,
+            max_position: self.max_position,
+            invert: self.invert,
+            offset: self.offset,
+            format: self.format,
+            pretty: self.pretty,
+            backup: self.backup,
+            merge: self.merge,
+            simplify: self.simplify,
+            prominence: self.prominence,
+            min_area: self.min_area,
+            max_actions: self.max_actions,
+            max_actions_per_second: self.max_actions_per_second,
+            max_speed: self.max_speed,
+            min_interval: self.min_interval,
+            interpolation: self.interpolation,
+        }
+    }
+}
+
+fn parse_rect(value: &str) -> Result<opencv::core::Rect, String> {
+    let values = value
+        .split(',')
+        .map(|v| v.trim().parse::<i32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| err.to_string())?;
+    match values[..] {
+        [x, y, width, height] if x >= 0 && y >= 0 && width > 0 && height > 0 => {
+            Ok(opencv::core::Rect::new(x, y, width, height))
         }
+        _ => Err("expected 'x,y,width,height'".to_string()),
     }
 }
 
+#[derive(clap::Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Compare a generated funscript with a reference funscript
+    Evaluate(EvaluateArgs),
+    /// Measure the speed of the pipeline stages on a video
+    Benchmark(BenchmarkArgs),
+}
+
+#[derive(Parser)]
+#[clap(name = "mtfg-rs", version, author)]
+struct CommandArgs {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+/// Subcommand of the command line, `None` for the default tracking mode
+pub fn parse_command() -> Option<Command> {
+    let first_arg = std::env::args().nth(1)?;
+    if first_arg != "evaluate" && first_arg != "benchmark" {
+        return None;
+    }
+    Some(CommandArgs::parse().command)
+}
+
 pub fn parse_args() -> Option<Args> {
     let result = Args::parse();
 
@@ -83,10 +336,125 @@ pub fn parse_args() -> Option<Args> {
         return None;
     }
 
+    if result.headless && !result.propose_roi && result.roi.len() != result.persons as usize {
+        error!("Headless mode requires one ROI per person or --propose-roi");
+        return None;
+    }
+
+    if result.propose_seconds <= 0.0 {
+        error!("Invalid propose-seconds value");
+        return None;
+    }
+
+    if !result.roi.is_empty() && result.roi.len() != result.persons as usize {
+        error!("Invalid number of ROIs");
+        return None;
+    }
+
+    if result.chunks < 1 {
+        error!("Invalid chunks value");
+        return None;
+    }
+
+    if result.chunks > 1 && !result.headless {
+        error!("Parallel chunks require the headless mode");
+        return None;
+    }
+
+    if result.fb_window < 2 || result.fb_window > MAX_FB_WINDOW {
+        error!("Invalid fb-window value");
+        return None;
+    }
+
+    if result.fb_threshold <= 0.0 {
+        error!("Invalid fb-threshold value");
+        return None;
+    }
+
+    if result.scene_cut_threshold <= 0.0 || result.scene_cut_threshold > 1.0 {
+        error!("Invalid scene-cut-threshold value");
+        return None;
+    }
+
+    if result.headless && result.scene_cut == SceneCutAction::Reselect {
+        error!("Selecting new ROIs after a scene cut is not possible in headless mode");
+        return None;
+    }
+
     if result.epsilon < 0.0 {
         error!("Invalid epsilon value");
         return None;
     }
 
+    if result.prominence < 0.0 {
+        error!("Invalid prominence value");
+        return None;
+    }
+
+    if result.min_area < 0.0 {
+        error!("Invalid min-area value");
+        return None;
+    }
+
+    if result.smoothing_window < 2 {
+        error!("Invalid smoothing-window value");
+        return None;
+    }
+
+    if result.kalman_process_noise <= 0.0 {
+        error!("Invalid kalman-process-noise value");
+        return None;
+    }
+
+    if result.one_euro_min_cutoff <= 0.0 || result.one_euro_beta < 0.0 {
+        error!("Invalid one euro smoothing parameters");
+        return None;
+    }
+
+    if result.detrend_window <= 0.0 {
+        error!("Invalid detrend-window value");
+        return None;
+    }
+
+    if result.detrend_degree > 5 {
+        error!("Invalid detrend-degree value");
+        return None;
+    }
+
+    if result.normalize_window <= 0.0 {
+        error!("Invalid normalize-window value");
+        return None;
+    }
+
+    if !(0.0..50.0).contains(&result.clip_percentile) {
+        error!("Invalid clip-percentile value");
+        return None;
+    }
+
+    if result.min_position >= result.max_position || result.max_position > 100 {
+        error!("Invalid min-position/max-position values");
+        return None;
+    }
+
+    if matches!(result.max_actions, Some(val) if val < 2) {
+        error!("Invalid max-actions value");
+        return None;
+    }
+
+    if matches!(result.max_actions_per_second, Some(val) if val <= 0.0) {
+        error!("Invalid max-actions-per-second value");
+        return None;
+    }
+
+    if matches!(result.max_speed, Some(val) if val <= 0.0) {
+        error!("Invalid max-speed value");
+        return None;
+    }
+
+    if matches!(result.min_interval, Some(val) if val < 0.0) {
+        error!("Invalid min-interval value");
+        return None;
+    }
+
     Some(result)
 }
@@ -0,0 +1,190 @@
+use crate::ffmpeg;
+use crate::projection::Reprojection;
+use crate::tracker::FeatureTracker;
+use crate::tracker::TrackerKind;
+use futures_util::StreamExt;
+use log::error;
+use log::info;
+use std::time::Instant;
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct BenchmarkArgs {
+    /// Path to Video File
+    #[clap(short = 'i', long = "input")]
+    pub input: String,
+
+    /// Start time in milliseconds
+    #[clap(short = 's', long = "start", default_value = "0")]
+    pub start_time: u32,
+
+    /// Number of frames per benchmark
+    #[clap(long = "frames", default_value = "300")]
+    pub frames: u32,
+
+    /// Video Filter with output 'w=\d:h=\d' parameter, the viewport placeholders use the
+    /// default viewport
+    #[clap(long = "filter", default_value = crate::args::DEFAULT_VIDEO_FILTER)]
+    pub video_filter: String,
+}
+
+fn log_result(name: &str, frames: u32, start: Instant) {
+    let seconds = start.elapsed().as_secs_f64();
+    info!(
+        "{:<28} {frames:>6} frames {:>9.1} ms {:>8.1} fps",
+        name,
+        seconds * 1000.0,
+        frames as f64 / seconds.max(0.000_001)
+    );
+}
+
+/// Run ffmpeg without output to measure decoding with and without the filter
+fn ffmpeg_null_output(args: &BenchmarkArgs, video_filter: Option<&str>) -> bool {
+    let start = format!("{}ms", args.start_time);
+    let frames = args.frames.to_string();
+    let mut cmd = std::process::Command::new("ffmpeg");
+    cmd.args(["-hide_banner", "-loglevel", "error", "-ss", start.as_str()])
+        .args(["-i", args.input.as_str(), "-frames:v", frames.as_str()])
+        .args(["-an", "-sn"]);
+    if let Some(video_filter) = video_filter {
+        cmd.args(["-vf", video_filter]);
+    }
+    cmd.args(["-f", "null", "-"])
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Filtered frames from the ffmpeg pipe like in the tracking pipeline
+async fn ffmpeg_pipe<F>(args: &BenchmarkArgs, video_filter: &str, mut handle_frame: F) -> u32
+where
+    F: FnMut(ffmpeg::FFmpegFrame<'static>),
+{
+    let Some(dimensions) = ffmpeg::get_dimension_from_video_filter(video_filter) else {
+        error!("Failed to parse video filter dimensions");
+        return 0;
+    };
+    let start = format!("{}ms", args.start_time);
+    let frames = args.frames.to_string();
+    let mut reader = ffmpeg::spawn_ffmpeg_frame_reader(
+        vec![
+            "-hide_banner",
+            "-loglevel",
+            "error",
+            "-ss",
+            start.as_str(),
+            "-i",
+            args.input.as_str(),
+            "-frames:v",
+            frames.as_str(),
+            "-f",
+            "image2pipe",
+            "-pix_fmt",
+            "bgr24",
+            "-vcodec",
+            "rawvideo",
+            "-an",
+            "-sn",
+            "-vf",
+            video_filter,
+            "-",
+        ],
+        dimensions,
+        None,
+    )
+    .await;
+
+    let mut count = 0;
+    while let Some(Ok(bytes_mut_buffer)) = reader.next().await {
+        let Some(frame_buffer) = ffmpeg::FrameBuffer::from_raw(
+            dimensions.width,
+            dimensions.height,
+            bytes_mut_buffer.to_vec(),
+        ) else {
+            break;
+        };
+        handle_frame(ffmpeg::FFmpegFrame::new(frame_buffer));
+        count += 1;
+    }
+    count
+}
+
+/// `benchmark` subcommand, measures each pipeline stage on its own
+pub async fn run(args: BenchmarkArgs) -> bool {
+    let video_filter = crate::ui::Viewport::default().apply_to_filter(args.video_filter.as_str());
+
+    let start = Instant::now();
+    if !ffmpeg_null_output(&args, None) {
+        error!("ffmpeg decoding failed");
+        return false;
+    }
+    log_result("ffmpeg decode", args.frames, start);
+
+    let start = Instant::now();
+    if !ffmpeg_null_output(&args, Some(video_filter.as_str())) {
+        error!("ffmpeg filter failed");
+        return false;
+    }
+    log_result("ffmpeg decode + filter", args.frames, start);
+
+    let start = Instant::now();
+    let frames = ffmpeg_pipe(&args, video_filter.as_str(), |_| {}).await;
+    log_result("ffmpeg decode + filter + pipe", frames, start);
+
+    match ffmpeg::get_single_frame(args.input.as_str(), args.start_time).await {
+        Ok(Some(frame)) => {
+            let mut reprojection = Reprojection::default();
+            let start = Instant::now();
+            if reprojection
+                .transform_frame(&frame.image, video_filter.as_str())
+                .is_some()
+            {
+                log_result("reprojection table setup", 1, start);
+                let start = Instant::now();
+                for _ in 0..args.frames {
+                    reprojection.transform_frame(&frame.image, video_filter.as_str());
+                }
+                log_result("in process reprojection", args.frames, start);
+            } else {
+                info!("in process reprojection does not support the filter");
+            }
+        }
+        _ => error!("Failed to extract first frame"),
+    }
+
+    for (kind, label) in [
+        (TrackerKind::Csrt, "csrt tracker update"),
+        (TrackerKind::OpticalFlow, "optical flow tracker update"),
+    ] {
+        let mut tracker: Option<FeatureTracker> = None;
+        let mut tracker_duration = std::time::Duration::ZERO;
+        let mut tracker_updates = 0;
+        ffmpeg_pipe(&args, video_filter.as_str(), |mut frame| {
+            let Some(active_tracker) = tracker.as_mut() else {
+                // track the center of the frame
+                let (width, height) = (frame.image.width() as i32, frame.image.height() as i32);
+                let mut new_tracker = FeatureTracker::new(kind);
+                let init_box =
+                    opencv::core::Rect::new(width * 3 / 8, height * 3 / 8, width / 4, height / 4);
+                if new_tracker.init(&mut frame, init_box) {
+                    tracker = Some(new_tracker);
+                }
+                return;
+            };
+            let mut bounding_box = opencv::core::Rect::default();
+            let start = Instant::now();
+            if active_tracker.update(&mut frame, &mut bounding_box) {
+                tracker_duration += start.elapsed();
+                tracker_updates += 1;
+            }
+        })
+        .await;
+        info!(
+            "{:<28} {tracker_updates:>6} frames {:>9.1} ms {:>8.1} fps",
+            label,
+            tracker_duration.as_secs_f64() * 1000.0,
+            tracker_updates as f64 / tracker_duration.as_secs_f64().max(0.000_001)
+        );
+    }
+
+    true
+}
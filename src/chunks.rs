@@ -0,0 +1,522 @@
+use crate::args::Args;
+use crate::ffmpeg;
+use crate::ffmpeg::FFmpegFrame;
+use crate::ffmpeg::FrameBuffer;
+use crate::stats;
+use crate::tracker;
+use crate::tracker::FeatureTracker;
+use log::error;
+use log::info;
+use log::warn;
+
+/// Overlap of neighbouring chunks, the trajectories are compared and stitched in this range
+const OVERLAP_SECONDS: f32 = 1.0;
+
+/// Maximum distance of the box centers in the overlap, relative to the box size, to accept
+/// the template matched start of a chunk
+const HANDOVER_TOLERANCE: f64 = 0.5;
+
+/// Minimum normalized correlation of the template match at the start of a chunk
+const MIN_TEMPLATE_SCORE: f64 = 0.5;
+
+type Templates = Option<Vec<FrameBuffer>>;
+
+/// Source of the initial tracking boxes of a chunk
+enum ChunkInit {
+    /// Known boxes on the first frame of the chunk
+    Boxes(Vec<opencv::core::Rect>),
+    /// Search the ROIs of the first chunk on the first frame of the chunk
+    Templates(tokio::sync::watch::Receiver<Templates>),
+}
+
+/// Tracking boxes of consecutive frames. The frames are numbered like in the sequential
+/// tracking, the ROI frame of the segment is frame 0 and the tracked frames start with 1.
+pub struct Chunk {
+    pub frames: Vec<u32>,
+    pub boxes: Vec<Vec<opencv::core::Rect>>,
+    /// Frames with a failed forward-backward check
+    pub flagged: Vec<bool>,
+    /// First frame after a scene cut, the tracking stops there
+    pub scene_cut: Option<u32>,
+}
+
+impl Chunk {
+    /// Frame numbers with a failed forward-backward check
+    pub fn flagged_frames(&self) -> Vec<u32> {
+        self.frames
+            .iter()
+            .zip(self.flagged.iter())
+            .filter(|(_, flagged)| **flagged)
+            .map(|(frame, _)| *frame)
+            .collect()
+    }
+
+    fn get(&self, frame: u32) -> Option<&Vec<opencv::core::Rect>> {
+        let idx = self.frames.binary_search(&frame).ok()?;
+        self.boxes.get(idx)
+    }
+
+    /// Replace all boxes from `switch_frame` on with the boxes of `other`
+    fn append(&mut self, other: Chunk, switch_frame: u32) {
+        let keep = self.frames.partition_point(|frame| *frame < switch_frame);
+        self.frames.truncate(keep);
+        self.boxes.truncate(keep);
+        self.flagged.truncate(keep);
+        for ((frame, boxes), flagged) in
+            other.frames.into_iter().zip(other.boxes).zip(other.flagged)
+        {
+            if frame >= switch_frame {
+                self.frames.push(frame);
+                self.boxes.push(boxes);
+                self.flagged.push(flagged);
+            }
+        }
+        if matches!(other.scene_cut, Some(frame) if frame >= switch_frame) {
+            self.scene_cut = other.scene_cut;
+        }
+    }
+
+    /// Run the forward-backward check for the boxes of the last `frames.len()` frames
+    fn check_window(
+        &mut self,
+        kind: tracker::TrackerKind,
+        frames: &mut Vec<FFmpegFrame>,
+        threshold: f64,
+    ) {
+        let start = self.boxes.len() - frames.len();
+        let features = self.boxes.last().map(|boxes| boxes.len()).unwrap_or(0);
+        let check_start = std::time::Instant::now();
+        for feature in 0..features {
+            let boxes = self.boxes[start..]
+                .iter()
+                .map(|boxes| boxes[feature])
+                .collect::<Vec<_>>();
+            let flags = tracker::backward_check(kind, frames, &boxes, threshold);
+            for (flagged, flag) in self.flagged[start..].iter_mut().zip(flags) {
+                *flagged |= flag;
+            }
+        }
+        stats::record("forward-backward check", check_start);
+        frames.clear();
+    }
+
+    /// Check that both chunks follow the same features in the frames they share
+    fn is_consistent(&self, other: &Chunk) -> bool {
+        let mut compared = 0;
+        for (frame, boxes) in other.frames.iter().zip(other.boxes.iter()) {
+            let Some(previous) = self.get(*frame) else {
+                continue;
+            };
+            for (a, b) in previous.iter().zip(boxes.iter()) {
+                let size = a.width.max(a.height) as f64;
+                if tracker::center_distance(a, b) > HANDOVER_TOLERANCE * size {
+                    return false;
+                }
+            }
+            compared += 1;
+        }
+        compared > 0
+    }
+}
+
+/// Split the segment into `--chunks` parts and track them in parallel. Each chunk start
+/// with a template match of the initial ROIs and overlap with the previous chunk.
+///
+/// The handover happens in the middle of the overlap. When a chunk follows the same
+/// features as the previous chunk in the overlap, the boxes of the chunk are used from there
+/// on. Only a chunk that fails this consistency check (or its template match) is tracked
+/// again, sequentially after the previous chunk, starting from the box of the previous chunk
+/// on the first frame of the chunk. The chunks after a scene cut are dropped.
+pub async fn track_chunks(args: &Args, video_fps: f32) -> Option<Chunk> {
+    let end_time = match args.end_time {
+        Some(end_time) => end_time,
+        None => match ffmpeg::get_video_duration(args.input.as_str()) {
+            Ok(duration) => duration,
+            Err(err) => {
+                error!("Could not determine video duration: {err}");
+                return None;
+            }
+        },
+    };
+
+    let step = args.frame_step_size;
+    let raw_frames = ((end_time - args.start_time) * video_fps / 1000.0).max(0.0) as u32;
+    let frames = raw_frames.div_ceil(step);
+    let overlap = ((OVERLAP_SECONDS * video_fps) as u32 / step).max(2);
+    let ranges = chunk_ranges(frames, args.chunks as u32, overlap);
+    if ranges.len() < args.chunks as usize {
+        warn!(
+            "segment too short for {} chunks, use {}",
+            args.chunks,
+            ranges.len()
+        );
+    }
+    info!(
+        "track {frames} frames in {} chunks with {overlap} frames overlap",
+        ranges.len()
+    );
+
+    let (templates_tx, templates_rx) = tokio::sync::watch::channel::<Templates>(None);
+    let mut templates_tx = Some(templates_tx);
+    let handles = ranges
+        .iter()
+        .map(|&(first_frame, last_frame)| {
+            let init = if first_frame == 0 {
+                ChunkInit::Boxes(args.roi.clone())
+            } else {
+                ChunkInit::Templates(templates_rx.clone())
+            };
+            spawn_chunk(
+                chunk_args(args, video_fps, first_frame, last_frame),
+                first_frame,
+                last_frame,
+                init,
+                templates_tx.take(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut results = vec![];
+    for handle in handles {
+        results.push(handle.await.ok().flatten());
+    }
+
+    let mut results = results.into_iter();
+    let Some(Some(mut stitched)) = results.next() else {
+        error!("Tracking of the first chunk failed");
+        return None;
+    };
+
+    for (k, (chunk, &(first_frame, last_frame))) in results.zip(ranges.iter().skip(1)).enumerate() {
+        let number = k + 2;
+        if let Some(scene_cut) = stitched.scene_cut {
+            info!("scene cut at frame {scene_cut}, stop tracking");
+            break;
+        }
+
+        let Some(handover) = stitched.get(first_frame).cloned() else {
+            warn!("tracking lost before chunk {number}, no handover");
+            if let Some(chunk) = chunk {
+                stitched.append(chunk, first_frame);
+            }
+            continue;
+        };
+
+        let chunk = match chunk {
+            Some(chunk) if stitched.is_consistent(&chunk) => chunk,
+            _ => {
+                warn!("chunk {number} does not match, track again from frame {first_frame}");
+                let repaired = spawn_chunk(
+                    chunk_args(args, video_fps, first_frame, last_frame),
+                    first_frame,
+                    last_frame,
+                    ChunkInit::Boxes(handover),
+                    None,
+                )
+                .await
+                .ok()
+                .flatten();
+                let Some(repaired) = repaired else {
+                    error!("Tracking of chunk {number} failed");
+                    continue;
+                };
+                repaired
+            }
+        };
+
+        stitched.append(chunk, first_frame + overlap / 2);
+    }
+
+    Some(stitched)
+}
+
+/// First and last frame of `chunks` chunks for a segment of `frames` frames, numbered like
+/// the sequential tracking. The first chunk start at the ROI frame 0, all chunks except the
+/// last one extend `overlap` frames into the next chunk.
+fn chunk_ranges(frames: u32, chunks: u32, overlap: u32) -> Vec<(u32, u32)> {
+    let last_frame = frames.saturating_sub(1);
+    let chunks = chunks.min(frames / (2 * overlap)).max(1);
+    let chunk_length = frames.div_ceil(chunks);
+    (0..chunks)
+        .map(|k| {
+            let first_frame = k * chunk_length;
+            (
+                first_frame,
+                ((k + 1) * chunk_length + overlap).min(last_frame),
+            )
+        })
+        .collect()
+}
+
+/// Arguments for the ffmpeg reader of a chunk. Frame `n` of the sequential tracking is the
+/// raw frame `n * step` after the segment start, seek half a frame before it because ffmpeg
+/// start with the first frame after the seek position.
+fn chunk_args(args: &Args, video_fps: f32, first_frame: u32, last_frame: u32) -> Args {
+    let ms_per_frame = 1000.0 / video_fps;
+    let step = args.frame_step_size;
+    let mut result = args.clone();
+    if first_frame > 0 {
+        let first_raw_frame = (args.start_time / ms_per_frame).ceil();
+        result.start_time = (first_raw_frame + (first_frame * step) as f32 - 0.5) * ms_per_frame;
+    }
+    result.end_time =
+        Some(result.start_time + ((last_frame - first_frame + 1) * step) as f32 * ms_per_frame);
+    result
+}
+
+fn spawn_chunk(
+    args: Args,
+    first_frame: u32,
+    last_frame: u32,
+    init: ChunkInit,
+    templates: Option<tokio::sync::watch::Sender<Templates>>,
+) -> tokio::task::JoinHandle<Option<Chunk>> {
+    tokio::task::spawn_blocking(move || {
+        tokio::runtime::Handle::current().block_on(track_chunk(
+            args,
+            first_frame,
+            last_frame,
+            init,
+            templates,
+        ))
+    })
+}
+
+/// Track all features from `first_frame` to `last_frame`, the first chunk share its ROIs
+/// with the other chunks through `templates`. Like in the sequential tracking the ROI frame 0
+/// of the first chunk is not part of the result.
+async fn track_chunk(
+    args: Args,
+    first_frame: u32,
+    last_frame: u32,
+    init: ChunkInit,
+    templates: Option<tokio::sync::watch::Sender<Templates>>,
+) -> Option<Chunk> {
+    let kind = args.tracker;
+    let fb_check = args.fb_check.then_some(tracker::ForwardBackwardCheck {
+        window: args.fb_window,
+        threshold: args.fb_threshold,
+    });
+    let mut frame_rx = ffmpeg::spawn_stream_reader(args);
+
+    let Some(mut frame) = frame_rx.recv().await else {
+        error!("chunk at frame {first_frame}: no frames");
+        return None;
+    };
+
+    let init_boxes = match init {
+        ChunkInit::Boxes(boxes) => boxes,
+        ChunkInit::Templates(mut receiver) => {
+            let templates = loop {
+                let current = receiver.borrow().clone();
+                if let Some(current) = current {
+                    break current;
+                }
+                if receiver.changed().await.is_err() {
+                    return None;
+                }
+            };
+
+            let mut boxes = vec![];
+            for template in templates.iter() {
+                let Some((init_box, score)) = match_template(&mut frame, template) else {
+                    error!("template match failed at frame {first_frame}");
+                    return None;
+                };
+                if score < MIN_TEMPLATE_SCORE {
+                    warn!("no template match at frame {first_frame} (score {score:.2})");
+                    return None;
+                }
+                boxes.push(init_box);
+            }
+            boxes
+        }
+    };
+
+    if let Some(templates) = templates {
+        let first_image = frame.image.as_rgb8()?;
+        let crops = init_boxes
+            .iter()
+            .map(|b| {
+                image::imageops::crop_imm(
+                    first_image,
+                    b.x as u32,
+                    b.y as u32,
+                    b.width as u32,
+                    b.height as u32,
+                )
+                .to_image()
+            })
+            .collect();
+        // NOTE: the send fails when there is only one chunk
+        let _ = templates.send(Some(crops));
+    }
+
+    let mut trackers = vec![];
+    for init_box in init_boxes.iter() {
+        let mut tracker = FeatureTracker::new(kind);
+        if !tracker.init(&mut frame, *init_box) {
+            error!("tracker setup failed");
+            return None;
+        }
+        trackers.push(tracker);
+    }
+
+    let stats_labels = (0..trackers.len())
+        .map(|feature| format!("tracker update feature {}", feature + 1))
+        .collect::<Vec<_>>();
+    let mut chunk = Chunk {
+        frames: vec![first_frame],
+        boxes: vec![init_boxes],
+        flagged: vec![false],
+        scene_cut: None,
+    };
+    let mut window_frames = vec![];
+    let mut frame_number = first_frame;
+    let mut lost = false;
+    while let Some(mut frame) = frame_rx.recv().await {
+        frame_number += 1;
+        if lost || frame_number > last_frame {
+            continue;
+        }
+
+        if frame.scene_cut {
+            chunk.scene_cut = Some(frame_number);
+            lost = true;
+            continue;
+        }
+
+        let mut boxes = chunk.boxes[chunk.boxes.len() - 1].clone();
+        for ((tracker, bounding_box), label) in trackers
+            .iter_mut()
+            .zip(boxes.iter_mut())
+            .zip(stats_labels.iter())
+        {
+            let update_start = std::time::Instant::now();
+            if !tracker.update(&mut frame, bounding_box) {
+                warn!("tracking lost at frame {frame_number}");
+                lost = true;
+                break;
+            }
+            stats::record(label.as_str(), update_start);
+        }
+
+        if !lost {
+            chunk.frames.push(frame_number);
+            chunk.boxes.push(boxes);
+            chunk.flagged.push(false);
+            if let Some(check) = fb_check {
+                window_frames.push(frame);
+                if window_frames.len() >= check.window {
+                    chunk.check_window(kind, &mut window_frames, check.threshold);
+                }
+            }
+        }
+    }
+
+    if let Some(check) = fb_check {
+        chunk.check_window(kind, &mut window_frames, check.threshold);
+    }
+
+    if first_frame == 0 {
+        chunk.frames.remove(0);
+        chunk.boxes.remove(0);
+        chunk.flagged.remove(0);
+    }
+
+    Some(chunk)
+}
+
+/// Best match of `template` in `frame` with its normalized correlation
+fn match_template(
+    frame: &mut FFmpegFrame,
+    template: &FrameBuffer,
+) -> Option<(opencv::core::Rect, f64)> {
+    let mut template_frame = FFmpegFrame::new(template.clone());
+    let mut result = opencv::core::Mat::default();
+    let mut success = true;
+    let mut opencv_frame = frame.get_opencv_frame();
+    let mut opencv_template = template_frame.get_opencv_frame();
+    opencv_frame.with_mut(|frame| {
+        opencv_template.with_mut(|template| {
+            success = opencv::imgproc::match_template(
+                frame.mat,
+                template.mat,
+                &mut result,
+                opencv::imgproc::TM_CCOEFF_NORMED,
+                &opencv::core::no_array(),
+            )
+            .is_ok();
+        });
+    });
+    if !success {
+        return None;
+    }
+
+    let mut score = 0.0;
+    let mut location = opencv::core::Point::default();
+    opencv::core::min_max_loc(
+        &result,
+        None,
+        Some(&mut score),
+        None,
+        Some(&mut location),
+        &opencv::core::no_array(),
+    )
+    .ok()?;
+
+    Some((
+        opencv::core::Rect::new(
+            location.x,
+            location.y,
+            template.width() as i32,
+            template.height() as i32,
+        ),
+        score,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn chunk_frames_match_sequential_tracking() {
+        let (frames, overlap) = (95, 4);
+        let ranges = chunk_ranges(frames, 4, overlap);
+        assert_eq!(ranges.len(), 4);
+        // sequential tracking: ROI frame 0, tracked frames 1..frames
+        assert_eq!(ranges[0].0, 0);
+        assert_eq!(ranges[ranges.len() - 1].1, frames - 1);
+        for pair in ranges.windows(2) {
+            // the handover frame in the middle of the overlap is tracked by both chunks
+            assert!(pair[1].0 + overlap / 2 <= pair[0].1);
+        }
+
+        let args = Args::parse_from([
+            "mtfg-rs",
+            "-i",
+            "video.mp4",
+            "-o",
+            "out.funscript",
+            "-s",
+            "1010",
+            "--step",
+            "3",
+            "--epsilon",
+            "3.0",
+        ]);
+        let video_fps = 30.0;
+        let ms_per_frame = 1000.0 / video_fps;
+        let first_raw_frame = (args.start_time / ms_per_frame).ceil() as u32;
+        for &(first_frame, last_frame) in ranges.iter() {
+            let chunk = chunk_args(&args, video_fps, first_frame, last_frame);
+            // first raw frame decoded after the seek and the raw frame of the last frame
+            let start = (chunk.start_time / ms_per_frame).ceil() as u32;
+            let end = (chunk.end_time.unwrap() / ms_per_frame).ceil() as u32;
+            assert_eq!(start, first_raw_frame + first_frame * args.frame_step_size);
+            assert!(end > first_raw_frame + last_frame * args.frame_step_size);
+        }
+    }
+}
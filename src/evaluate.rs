@@ -0,0 +1,436 @@
+use crate::funscript::Funscript;
+use crate::funscript::FunscriptAction;
+use log::error;
+use log::info;
+use serde::Serialize;
+use std::io::Write;
+
+/// Sample interval in milliseconds for the position correlation and the DTW distance
+const SAMPLE_INTERVAL_IN_MS: u32 = 50;
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct EvaluateArgs {
+    /// Generated funscript
+    #[clap(short = 'g', long = "generated")]
+    pub generated: String,
+
+    /// Reference funscript (e.g. a hand made script)
+    #[clap(short = 'r', long = "reference")]
+    pub reference: String,
+
+    /// Start time in milliseconds of the compared range
+    #[clap(short = 's', long = "start")]
+    pub start_time: Option<u32>,
+
+    /// End time in milliseconds of the compared range
+    #[clap(long = "end")]
+    pub end_time: Option<u32>,
+
+    /// Minimum stroke height to count as stroke
+    #[clap(long = "prominence", default_value = "10.0")]
+    pub prominence: f64,
+
+    /// Maximum time difference in milliseconds between matched strokes
+    #[clap(long = "tolerance", default_value = "200")]
+    pub tolerance: u32,
+
+    /// Maximum time shift in milliseconds of the DTW alignment
+    #[clap(long = "dtw-window", default_value = "1000")]
+    pub dtw_window: u32,
+
+    /// Write the report as JSON to the given file, use `-` for stdout
+    #[clap(long = "json")]
+    pub json: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct EvaluationReport {
+    pub start_time: u32,
+    pub end_time: u32,
+    pub reference_strokes: usize,
+    pub generated_strokes: usize,
+    pub matched_strokes: usize,
+    pub missed_strokes: usize,
+    pub extra_strokes: usize,
+    /// Mean signed time difference (generated - reference) of matched strokes in ms
+    pub timing_error_mean: f64,
+    /// Mean absolute time difference of matched strokes in ms
+    pub timing_error_mean_abs: f64,
+    /// Root mean square time difference of matched strokes in ms
+    pub timing_error_rms: f64,
+    /// Pearson correlation of the sampled positions
+    pub position_correlation: Option<f64>,
+    /// Pearson correlation of the matched stroke amplitudes
+    pub amplitude_correlation: Option<f64>,
+    /// Mean absolute position difference along the DTW alignment
+    pub dtw_distance: Option<f64>,
+}
+
+/// Turning point of the script
+#[derive(Copy, Clone, Debug)]
+struct Stroke {
+    at: f64,
+    /// +1.0 for a top and -1.0 for a bottom turning point
+    direction: f64,
+    /// Position difference to the previous turning point
+    amplitude: f64,
+}
+
+fn strokes(actions: &[FunscriptAction], prominence: f64) -> Vec<Stroke> {
+    let points = actions
+        .iter()
+        .map(|a| mint::Point2 {
+            x: a.at as f64,
+            y: a.pos as f64,
+        })
+        .collect::<Vec<_>>();
+    let turning_points = crate::simplify::peaks(points, prominence);
+
+    // the first and last point are not necessarily turning points
+    let n = turning_points.len();
+    (1..n.saturating_sub(1))
+        .map(|i| Stroke {
+            at: turning_points[i].x,
+            direction: (turning_points[i].y - turning_points[i - 1].y).signum(),
+            amplitude: (turning_points[i].y - turning_points[i - 1].y).abs(),
+        })
+        .collect()
+}
+
+/// Greedy matching of turning points with the same direction in time order, returns the
+/// index pairs (reference, generated)
+fn match_strokes(
+    reference: &[Stroke],
+    generated: &[Stroke],
+    tolerance: f64,
+) -> Vec<(usize, usize)> {
+    let mut matches = vec![];
+    let mut used = vec![false; generated.len()];
+    let mut start = 0;
+    for (r_idx, r) in reference.iter().enumerate() {
+        while start < generated.len() && generated[start].at < r.at - tolerance {
+            start += 1;
+        }
+        let best = (start..generated.len())
+            .take_while(|idx| generated[*idx].at <= r.at + tolerance)
+            .filter(|idx| !used[*idx] && generated[*idx].direction == r.direction)
+            .min_by(|a, b| {
+                (generated[*a].at - r.at)
+                    .abs()
+                    .total_cmp(&(generated[*b].at - r.at).abs())
+            });
+        if let Some(g_idx) = best {
+            used[g_idx] = true;
+            matches.push((r_idx, g_idx));
+        }
+    }
+    matches
+}
+
+/// Position at `at` with linear interpolation
+fn position_at(actions: &[FunscriptAction], at: u32) -> f64 {
+    let idx = actions.partition_point(|a| a.at <= at);
+    if idx == 0 {
+        return actions[0].pos as f64;
+    }
+    if idx == actions.len() {
+        return actions[idx - 1].pos as f64;
+    }
+    let (a, b) = (&actions[idx - 1], &actions[idx]);
+    let t = (at - a.at) as f64 / (b.at - a.at) as f64;
+    a.pos as f64 + (b.pos as f64 - a.pos as f64) * t
+}
+
+fn correlation(a: &[f64], b: &[f64]) -> Option<f64> {
+    if a.len() < 2 || a.len() != b.len() {
+        return None;
+    }
+    let n = a.len() as f64;
+    let mean_a = a.iter().sum::<f64>() / n;
+    let mean_b = b.iter().sum::<f64>() / n;
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b.iter()) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a).powi(2);
+        var_b += (y - mean_b).powi(2);
+    }
+    if var_a <= 0.0 || var_b <= 0.0 {
+        return None;
+    }
+    Some(cov / (var_a * var_b).sqrt())
+}
+
+/// Dynamic time warping with a Sakoe-Chiba band of `window` samples, returns the mean
+/// absolute difference along the warping path
+fn dtw(a: &[f64], b: &[f64], window: usize) -> Option<f64> {
+    if a.is_empty() || b.is_empty() {
+        return None;
+    }
+    let window = window.max(a.len().abs_diff(b.len()));
+    // (cost, path length) of the previous and current row
+    let mut previous = vec![(f64::INFINITY, 0usize); b.len() + 1];
+    let mut current = previous.clone();
+    previous[0] = (0.0, 0);
+    for i in 1..=a.len() {
+        current.fill((f64::INFINITY, 0));
+        for j in i.saturating_sub(window).max(1)..=(i + window).min(b.len()) {
+            let cost = (a[i - 1] - b[j - 1]).abs();
+            let best = [previous[j - 1], previous[j], current[j - 1]]
+                .into_iter()
+                .min_by(|x, y| x.0.total_cmp(&y.0))
+                .unwrap();
+            current[j] = (best.0 + cost, best.1 + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    let (cost, length) = previous[b.len()];
+    if length == 0 || !cost.is_finite() {
+        return None;
+    }
+    Some(cost / length as f64)
+}
+
+pub fn evaluate(
+    generated: &[FunscriptAction],
+    reference: &[FunscriptAction],
+    args: &EvaluateArgs,
+) -> EvaluationReport {
+    let in_range = |a: &&FunscriptAction| {
+        a.at >= args.start_time.unwrap_or(0) && a.at <= args.end_time.unwrap_or(u32::MAX)
+    };
+    let generated = generated
+        .iter()
+        .filter(in_range)
+        .copied()
+        .collect::<Vec<_>>();
+    let reference = reference
+        .iter()
+        .filter(in_range)
+        .copied()
+        .collect::<Vec<_>>();
+
+    let mut report = EvaluationReport::default();
+    let (Some(first), Some(last)) = (reference.first(), reference.last()) else {
+        error!("reference funscript has no actions in the selected range");
+        return report;
+    };
+    report.start_time = args.start_time.unwrap_or(first.at);
+    report.end_time = args.end_time.unwrap_or(last.at).min(last.at);
+
+    let reference_strokes = strokes(&reference, args.prominence);
+    let generated_strokes = strokes(&generated, args.prominence);
+    let matches = match_strokes(
+        &reference_strokes,
+        &generated_strokes,
+        args.tolerance as f64,
+    );
+
+    report.reference_strokes = reference_strokes.len();
+    report.generated_strokes = generated_strokes.len();
+    report.matched_strokes = matches.len();
+    report.missed_strokes = reference_strokes.len() - matches.len();
+    report.extra_strokes = generated_strokes.len() - matches.len();
+
+    if !matches.is_empty() {
+        let errors = matches
+            .iter()
+            .map(|(r, g)| generated_strokes[*g].at - reference_strokes[*r].at)
+            .collect::<Vec<_>>();
+        let n = errors.len() as f64;
+        report.timing_error_mean = errors.iter().sum::<f64>() / n;
+        report.timing_error_mean_abs = errors.iter().map(|e| e.abs()).sum::<f64>() / n;
+        report.timing_error_rms = (errors.iter().map(|e| e * e).sum::<f64>() / n).sqrt();
+        report.amplitude_correlation = correlation(
+            &matches
+                .iter()
+                .map(|(r, _)| reference_strokes[*r].amplitude)
+                .collect::<Vec<_>>(),
+            &matches
+                .iter()
+                .map(|(_, g)| generated_strokes[*g].amplitude)
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    if !generated.is_empty() {
+        let samples = (report.start_time..=report.end_time)
+            .step_by(SAMPLE_INTERVAL_IN_MS as usize)
+            .collect::<Vec<_>>();
+        let reference_positions = samples
+            .iter()
+            .map(|at| position_at(&reference, *at))
+            .collect::<Vec<_>>();
+        let generated_positions = samples
+            .iter()
+            .map(|at| position_at(&generated, *at))
+            .collect::<Vec<_>>();
+        report.position_correlation = correlation(&reference_positions, &generated_positions);
+        report.dtw_distance = dtw(
+            &reference_positions,
+            &generated_positions,
+            (args.dtw_window / SAMPLE_INTERVAL_IN_MS) as usize,
+        );
+    }
+
+    report
+}
+
+/// `evaluate` subcommand
+pub fn run(args: EvaluateArgs) -> bool {
+    let generated = match Funscript::load(args.generated.as_str()) {
+        Ok(funscript) => funscript,
+        Err(err) => {
+            error!("Could not load {}: {err}", args.generated);
+            return false;
+        }
+    };
+    let reference = match Funscript::load(args.reference.as_str()) {
+        Ok(funscript) => funscript,
+        Err(err) => {
+            error!("Could not load {}: {err}", args.reference);
+            return false;
+        }
+    };
+
+    let mut generated_actions = generated.actions().to_vec();
+    let mut reference_actions = reference.actions().to_vec();
+    generated_actions.sort_by_key(|a| a.at);
+    reference_actions.sort_by_key(|a| a.at);
+
+    let report = evaluate(&generated_actions, &reference_actions, &args);
+
+    // NOTE: the log output also goes to stdout
+    if args.json.as_deref() != Some("-") {
+        info!("{report:#?}");
+    }
+
+    if let Some(json) = args.json {
+        let serialized_report = serde_json::to_string_pretty(&report).unwrap();
+        let result = if json == "-" {
+            // NOTE: hold the lock for the whole report so no log line is written into it
+            let mut stdout = std::io::stdout().lock();
+            writeln!(stdout, "{serialized_report}").and_then(|_| stdout.flush())
+        } else {
+            std::fs::write(json.as_str(), serialized_report)
+        };
+        if let Err(err) = result {
+            error!("Unable to write report {json}: {err}");
+            return false;
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actions(actions: &[(u32, u8)]) -> Vec<FunscriptAction> {
+        actions
+            .iter()
+            .map(|(at, pos)| FunscriptAction { at: *at, pos: *pos })
+            .collect()
+    }
+
+    fn stroke(at: f64, direction: f64) -> Stroke {
+        Stroke {
+            at,
+            direction,
+            amplitude: 50.0,
+        }
+    }
+
+    fn args() -> EvaluateArgs {
+        EvaluateArgs {
+            generated: String::new(),
+            reference: String::new(),
+            start_time: None,
+            end_time: None,
+            prominence: 10.0,
+            tolerance: 200,
+            dtw_window: 1000,
+            json: None,
+        }
+    }
+
+    #[test]
+    fn correlation_fixtures() {
+        let a = [0.0, 50.0, 100.0, 20.0];
+        assert!((correlation(&a, &a).unwrap() - 1.0).abs() < 1e-12);
+        let inverted = a.iter().map(|v| 100.0 - v).collect::<Vec<_>>();
+        assert!((correlation(&a, &inverted).unwrap() + 1.0).abs() < 1e-12);
+        assert_eq!(correlation(&a, &[5.0; 4]), None);
+        assert_eq!(correlation(&a, &a[1..]), None);
+    }
+
+    #[test]
+    fn dtw_fixtures() {
+        let a = [0.0, 0.0, 10.0, 0.0];
+        let shifted = [0.0, 10.0, 0.0, 0.0];
+        assert_eq!(dtw(&a, &a, 0), Some(0.0));
+        // the warping path absorbs a shift within the window
+        assert_eq!(dtw(&a, &shifted, 1), Some(0.0));
+        // without a window the path is the diagonal: (0 + 10 + 10 + 0) / 4
+        assert_eq!(dtw(&a, &shifted, 0), Some(5.0));
+        // a constant offset of 5 costs 5 on every step
+        let offset = a.iter().map(|v| v + 5.0).collect::<Vec<_>>();
+        assert_eq!(dtw(&a, &offset, 1), Some(5.0));
+        assert_eq!(dtw(&a, &[], 1), None);
+    }
+
+    #[test]
+    fn match_strokes_fixtures() {
+        let reference = [stroke(100.0, 1.0), stroke(200.0, -1.0), stroke(300.0, 1.0)];
+        let generated = [
+            stroke(130.0, 1.0),
+            stroke(190.0, 1.0),
+            stroke(210.0, -1.0),
+            stroke(600.0, 1.0),
+        ];
+        // same direction within the tolerance, the closest stroke wins and is used once
+        assert_eq!(
+            match_strokes(&reference, &generated, 50.0),
+            [(0, 0), (1, 2)]
+        );
+        assert_eq!(
+            match_strokes(&reference, &generated, 300.0),
+            [(0, 0), (1, 2), (2, 1)]
+        );
+        assert!(match_strokes(&reference, &[], 50.0).is_empty());
+    }
+
+    #[test]
+    fn evaluate_identical_and_shifted_scripts() {
+        let reference = actions(&[
+            (0, 50),
+            (500, 100),
+            (1000, 0),
+            (1500, 80),
+            (2000, 20),
+            (2500, 60),
+            (3000, 50),
+        ]);
+        let report = evaluate(&reference, &reference, &args());
+        assert_eq!(report.reference_strokes, 5);
+        assert_eq!(report.matched_strokes, 5);
+        assert_eq!(report.missed_strokes + report.extra_strokes, 0);
+        assert_eq!(report.timing_error_rms, 0.0);
+        assert!((report.position_correlation.unwrap() - 1.0).abs() < 1e-12);
+        assert!((report.amplitude_correlation.unwrap() - 1.0).abs() < 1e-12);
+        assert_eq!(report.dtw_distance, Some(0.0));
+
+        let shifted = reference
+            .iter()
+            .map(|a| FunscriptAction {
+                at: a.at + 100,
+                pos: a.pos,
+            })
+            .collect::<Vec<_>>();
+        let report = evaluate(&shifted, &reference, &args());
+        assert_eq!(report.matched_strokes, 5);
+        assert_eq!(report.timing_error_mean, 100.0);
+        assert_eq!(report.timing_error_rms, 100.0);
+        assert!((report.amplitude_correlation.unwrap() - 1.0).abs() < 1e-12);
+    }
+}
@@ -20,11 +20,14 @@ use tokio_util::codec::Decoder;
 use tokio_util::codec::FramedRead;
 
 use crate::args;
+use crate::scene_cut::SceneCutAction;
+use crate::scene_cut::SceneCutDetector;
+use crate::stats;
 
 pub type Bgr = Rgb<u8>;
 pub type FrameBuffer = ImageBuffer<Bgr, Vec<u8>>;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Dimensions {
     pub width: u32,
     pub height: u32,
@@ -72,6 +75,8 @@ pub struct OpencvMatWithLifetime<'a> {
 #[derive(Clone)]
 pub struct FFmpegFrame<'a> {
     pub image: Arc<DynamicImage>,
+    /// First frame after a detected scene cut
+    pub scene_cut: bool,
     image_lifetime: PhantomData<&'a DynamicImage>,
 }
 
@@ -80,10 +85,17 @@ impl<'a> FFmpegFrame<'a> {
         Self {
             // NOTE: We store bgr image in rgb buffer!
             image: Arc::new(DynamicImage::ImageRgb8(frame_buffer)),
+            scene_cut: false,
             image_lifetime: PhantomData,
         }
     }
 
+    /// Clone with its own pixel buffer, required before drawing on a frame that is shared
+    /// with the trackers
+    pub fn deep_copy(&self) -> Self {
+        Self::new(self.image.as_rgb8().unwrap().clone())
+    }
+
     pub fn get_opencv_frame(&mut self) -> Fortify<OpencvMatWithLifetime> {
         unsafe {
             fortify! {
@@ -102,6 +114,23 @@ impl<'a> FFmpegFrame<'a> {
             }
         }
     }
+
+    /// Single channel gray copy of the frame for the optical flow
+    pub fn get_gray_frame(&mut self) -> Option<opencv::core::Mat> {
+        let mut gray = opencv::core::Mat::default();
+        let mut success = true;
+        let mut opencv_frame = self.get_opencv_frame();
+        opencv_frame.with_mut(|frame| {
+            success = opencv::imgproc::cvt_color(
+                frame.mat,
+                &mut gray,
+                opencv::imgproc::COLOR_BGR2GRAY,
+                0,
+            )
+            .is_ok();
+        });
+        success.then_some(gray)
+    }
 }
 
 pub fn get_video_fps(video_path: &str) -> Result<f32, Box<dyn std::error::Error>> {
@@ -131,14 +160,43 @@ pub fn get_video_fps(video_path: &str) -> Result<f32, Box<dyn std::error::Error>
     cmd.wait()?;
 
     match fps.to_f32() {
-        Some(val) => Ok(val),
-        None => {
+        Some(val) if val.is_finite() && val > 0.0 => Ok(val),
+        _ => {
             warn!("could not determine fps of video {video_path}");
             Ok(30.0)
         }
     }
 }
 
+/// Duration of the video in milliseconds
+pub fn get_video_duration(video_path: &str) -> Result<f32, Box<dyn std::error::Error>> {
+    let mut cmd = std::process::Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+            video_path,
+        ])
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let stdout = cmd.stdout.as_mut().unwrap();
+    let stdout_reader = BufReader::new(stdout);
+    let duration = stdout_reader
+        .lines()
+        .next()
+        .ok_or("no duration in ffprobe output")??
+        .trim()
+        .parse::<f32>()?;
+
+    cmd.wait()?;
+
+    Ok(duration * 1000.0)
+}
+
 pub fn get_video_dimensions(video_path: &str) -> Result<Dimensions, Box<dyn std::error::Error>> {
     let mut cmd = std::process::Command::new("ffprobe")
         .args([
@@ -349,7 +407,7 @@ fn millisec_to_timestamp(val: u32) -> String {
     format!("{hours:0>2}:{minutes:0>2}:{seconds:0>2}.{millis:0>3}")
 }
 
-fn get_dimension_from_video_filter(video_filter: &str) -> Option<Dimensions> {
+pub fn get_dimension_from_video_filter(video_filter: &str) -> Option<Dimensions> {
     let mut video_dimensions: Option<Dimensions> = None;
     let re = regex::Regex::new(r"w=(\d+):h=(\d+)").unwrap();
     for cap in re.captures_iter(video_filter) {
@@ -430,16 +488,36 @@ pub async fn ffmpeg_stream_reader(
 
     info!("start ffmpeg");
 
+    let mut scene_cut_detector = (args.scene_cut != SceneCutAction::None)
+        .then(|| SceneCutDetector::new(args.scene_cut_threshold));
+    // NOTE: cuts in the frames skipped by the frame step size are reported on the next frame
+    let mut scene_cut = false;
     let mut frame_number = 0;
-    while let Some(Ok(bytes_mut_buffer)) = reader.next().await {
+    loop {
+        let read_start = std::time::Instant::now();
+        let Some(Ok(bytes_mut_buffer)) = reader.next().await else {
+            break;
+        };
+        // NOTE: decoding and filtering happen in the ffmpeg process, use the benchmark
+        // subcommand to measure them separately
+        stats::record("ffmpeg decode + filter + pipe", read_start);
+
+        let copy_start = std::time::Instant::now();
         let frame_buffer: FrameBuffer = FrameBuffer::from_raw(
             video_dimensions.width,
             video_dimensions.height,
             bytes_mut_buffer.to_vec(),
         )
         .expect("ffmpeg: parse frame error");
+        stats::record("frame buffer copy", copy_start);
         frame_number += 1;
 
+        if let Some(detector) = scene_cut_detector.as_mut() {
+            let detect_start = std::time::Instant::now();
+            scene_cut |= detector.is_cut(&frame_buffer);
+            stats::record("scene cut detection", detect_start);
+        }
+
         if ((frame_number - 1) % args.frame_step_size) != 0 {
             continue;
         }
@@ -450,7 +528,10 @@ pub async fn ffmpeg_stream_reader(
         }
 
         let mut should_exit = false;
-        let ffmpeg_frame = FFmpegFrame::new(frame_buffer);
+        let mut ffmpeg_frame = FFmpegFrame::new(frame_buffer);
+        ffmpeg_frame.scene_cut = scene_cut;
+        scene_cut = false;
+        let send_start = std::time::Instant::now();
         for producer in &producers {
             if producer.send(ffmpeg_frame.clone()).await.is_err() {
                 error!("ffmpeg: error adding frame to process queue");
@@ -458,6 +539,8 @@ pub async fn ffmpeg_stream_reader(
                 break;
             }
         }
+        // waiting here means the trackers or the preview are the bottleneck
+        stats::record("frame queue wait", send_start);
 
         if should_exit {
             break;
@@ -466,3 +549,16 @@ pub async fn ffmpeg_stream_reader(
 
     info!("stop ffmpeg");
 }
+
+/// Number of frames ffmpeg can decode ahead of the consumer of `spawn_stream_reader`
+const FRAME_QUEUE_CAPACITY: usize = 16;
+
+/// Decode the segment of `args` in the background for a single consumer. NOTE: receive all
+/// frames until the channel is closed so the ffmpeg reader can stop by itself
+pub fn spawn_stream_reader(args: args::Args) -> tokio::sync::mpsc::Receiver<FFmpegFrame<'static>> {
+    let (frame_tx, frame_rx) = tokio::sync::mpsc::channel::<FFmpegFrame>(FRAME_QUEUE_CAPACITY);
+    tokio::task::spawn_blocking(move || {
+        tokio::runtime::Handle::current().block_on(ffmpeg_stream_reader(args, vec![frame_tx]));
+    });
+    frame_rx
+}
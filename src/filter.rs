@@ -0,0 +1,354 @@
+use crate::trajectories::TrackingTrajectories;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SmoothingMethod {
+    None,
+    /// Centered moving average over `--smoothing-window` samples
+    MovingAverage,
+    /// Quadratic Savitzky–Golay filter over `--smoothing-window` samples
+    SavitzkyGolay,
+    /// Constant velocity Kalman filter with Rauch–Tung–Striebel smoothing
+    Kalman,
+    /// Speed adaptive low-pass (one euro filter)
+    OneEuro,
+}
+
+/// Filter settings for the box center trajectories
+#[derive(Copy, Clone, Debug)]
+pub struct Smoothing {
+    pub method: SmoothingMethod,
+    /// Number of samples for the moving average and Savitzky–Golay filter
+    pub window: usize,
+    /// Process noise (acceleration variance in px²/s⁴) of the Kalman filter
+    pub process_noise: f64,
+    /// Minimum cutoff frequency in Hz of the one euro filter
+    pub min_cutoff: f64,
+    /// Speed coefficient of the one euro filter
+    pub beta: f64,
+}
+
+/// Measurement noise variance in px² of the Kalman filter, the box positions are integers
+const MEASUREMENT_NOISE: f64 = 1.0;
+
+impl Smoothing {
+    /// Filter the center points of each feature, the filters restart after skipped frames
+    pub fn smooth_trajectories(&self, trajectories: &mut TrackingTrajectories, video_fps: f32) {
+        if self.method == SmoothingMethod::None || trajectories.frames.is_empty() {
+            return;
+        }
+
+        let time = trajectories
+            .frames
+            .iter()
+            .map(|frame| (frame * trajectories.timestep) as f64 / video_fps as f64)
+            .collect::<Vec<_>>();
+
+        let mut start = 0;
+        for end in 1..=trajectories.frames.len() {
+            if end < trajectories.frames.len()
+                && trajectories.frames[end] == trajectories.frames[end - 1] + 1
+            {
+                continue;
+            }
+
+            for feature in 0..trajectories.trackers {
+                let segment = &mut trajectories.trajectories[start..end];
+                if segment.iter().any(|points| points.len() <= feature) {
+                    continue;
+                }
+
+                let x = segment
+                    .iter()
+                    .map(|p| p[feature].x as f64)
+                    .collect::<Vec<_>>();
+                let y = segment
+                    .iter()
+                    .map(|p| p[feature].y as f64)
+                    .collect::<Vec<_>>();
+                let x = self.apply(&time[start..end], &x);
+                let y = self.apply(&time[start..end], &y);
+                for ((points, x), y) in segment.iter_mut().zip(x).zip(y) {
+                    points[feature] = mint::Point2 {
+                        x: x.round() as i32,
+                        y: y.round() as i32,
+                    };
+                }
+            }
+
+            start = end;
+        }
+    }
+
+    /// Filter `values` sampled at `time` (in seconds)
+    pub fn apply(&self, time: &[f64], values: &[f64]) -> Vec<f64> {
+        if values.len() < 2 {
+            return values.to_vec();
+        }
+
+        match self.method {
+            SmoothingMethod::None => values.to_vec(),
+            SmoothingMethod::MovingAverage => moving_average(values, self.window / 2),
+            SmoothingMethod::SavitzkyGolay => savitzky_golay(values, self.window / 2),
+            SmoothingMethod::Kalman => kalman(time, values, self.process_noise),
+            SmoothingMethod::OneEuro => one_euro(time, values, self.min_cutoff, self.beta),
+        }
+    }
+}
+
+fn moving_average(values: &[f64], half_window: usize) -> Vec<f64> {
+    (0..values.len())
+        .map(|i| {
+            let window =
+                &values[i.saturating_sub(half_window)..(i + half_window + 1).min(values.len())];
+            window.iter().sum::<f64>() / window.len() as f64
+        })
+        .collect()
+}
+
+/// Quadratic Savitzky–Golay smoothing, the window shrink symmetrically at the borders
+fn savitzky_golay(values: &[f64], half_window: usize) -> Vec<f64> {
+    let n = values.len();
+    (0..n)
+        .map(|i| {
+            let m = half_window.min(i).min(n - 1 - i) as f64;
+            if m < 1.0 {
+                return values[i];
+            }
+            let norm = (2.0 * m - 1.0) * (2.0 * m + 1.0) * (2.0 * m + 3.0);
+            let m_int = m as i64;
+            (-m_int..=m_int)
+                .map(|k| {
+                    let coefficient =
+                        (3.0 * (3.0 * m * m + 3.0 * m - 1.0) - 15.0 * (k * k) as f64) / norm;
+                    coefficient * values[(i as i64 + k) as usize]
+                })
+                .sum()
+        })
+        .collect()
+}
+
+type Matrix = [[f64; 2]; 2];
+
+/// Constant velocity Kalman filter followed by a Rauch–Tung–Striebel smoother, so the
+/// result has no lag
+fn kalman(time: &[f64], values: &[f64], process_noise: f64) -> Vec<f64> {
+    let n = values.len();
+    let mut filtered_state = Vec::with_capacity(n);
+    let mut filtered_cov: Vec<Matrix> = Vec::with_capacity(n);
+    let mut predicted_state = Vec::with_capacity(n);
+    let mut predicted_cov: Vec<Matrix> = Vec::with_capacity(n);
+    let mut transitions: Vec<Matrix> = Vec::with_capacity(n);
+
+    let mut state = [values[0], 0.0];
+    let mut cov: Matrix = [[MEASUREMENT_NOISE, 0.0], [0.0, 1e6]];
+    for i in 0..n {
+        let dt = if i == 0 { 0.0 } else { time[i] - time[i - 1] };
+        let f: Matrix = [[1.0, dt], [0.0, 1.0]];
+        let q = process_noise;
+        let noise: Matrix = [
+            [q * dt.powi(3) / 3.0, q * dt.powi(2) / 2.0],
+            [q * dt.powi(2) / 2.0, q * dt],
+        ];
+
+        // predict
+        if i > 0 {
+            state = [state[0] + dt * state[1], state[1]];
+            cov = add(&multiply(&multiply(&f, &cov), &transpose(&f)), &noise);
+        }
+        predicted_state.push(state);
+        predicted_cov.push(cov);
+        transitions.push(f);
+
+        // update with the measured position
+        let innovation = values[i] - state[0];
+        let s = cov[0][0] + MEASUREMENT_NOISE;
+        let gain = [cov[0][0] / s, cov[1][0] / s];
+        state = [
+            state[0] + gain[0] * innovation,
+            state[1] + gain[1] * innovation,
+        ];
+        cov = [
+            [(1.0 - gain[0]) * cov[0][0], (1.0 - gain[0]) * cov[0][1]],
+            [
+                cov[1][0] - gain[1] * cov[0][0],
+                cov[1][1] - gain[1] * cov[0][1],
+            ],
+        ];
+        filtered_state.push(state);
+        filtered_cov.push(cov);
+    }
+
+    let mut smoothed = filtered_state.clone();
+    for i in (0..n - 1).rev() {
+        let Some(inverse) = invert(&predicted_cov[i + 1]) else {
+            continue;
+        };
+        let c = multiply(
+            &multiply(&filtered_cov[i], &transpose(&transitions[i + 1])),
+            &inverse,
+        );
+        let diff = [
+            smoothed[i + 1][0] - predicted_state[i + 1][0],
+            smoothed[i + 1][1] - predicted_state[i + 1][1],
+        ];
+        smoothed[i] = [
+            filtered_state[i][0] + c[0][0] * diff[0] + c[0][1] * diff[1],
+            filtered_state[i][1] + c[1][0] * diff[0] + c[1][1] * diff[1],
+        ];
+    }
+
+    smoothed.into_iter().map(|state| state[0]).collect()
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    [
+        [
+            a[0][0] * b[0][0] + a[0][1] * b[1][0],
+            a[0][0] * b[0][1] + a[0][1] * b[1][1],
+        ],
+        [
+            a[1][0] * b[0][0] + a[1][1] * b[1][0],
+            a[1][0] * b[0][1] + a[1][1] * b[1][1],
+        ],
+    ]
+}
+
+fn add(a: &Matrix, b: &Matrix) -> Matrix {
+    [
+        [a[0][0] + b[0][0], a[0][1] + b[0][1]],
+        [a[1][0] + b[1][0], a[1][1] + b[1][1]],
+    ]
+}
+
+fn transpose(a: &Matrix) -> Matrix {
+    [[a[0][0], a[1][0]], [a[0][1], a[1][1]]]
+}
+
+fn invert(a: &Matrix) -> Option<Matrix> {
+    let det = a[0][0] * a[1][1] - a[0][1] * a[1][0];
+    if det.abs() < f64::EPSILON {
+        return None;
+    }
+    Some([
+        [a[1][1] / det, -a[0][1] / det],
+        [-a[1][0] / det, a[0][0] / det],
+    ])
+}
+
+/// One euro filter (Casiez et al.), strong smoothing at low speed and low lag at high speed
+fn one_euro(time: &[f64], values: &[f64], min_cutoff: f64, beta: f64) -> Vec<f64> {
+    const DERIVATE_CUTOFF: f64 = 1.0;
+    let alpha = |cutoff: f64, dt: f64| {
+        let tau = 1.0 / (2.0 * std::f64::consts::PI * cutoff);
+        1.0 / (1.0 + tau / dt)
+    };
+
+    let mut result = Vec::with_capacity(values.len());
+    let mut previous = values[0];
+    let mut derivate = 0.0;
+    result.push(previous);
+    for i in 1..values.len() {
+        let dt = time[i] - time[i - 1];
+        if dt <= 0.0 {
+            result.push(previous);
+            continue;
+        }
+        let a_d = alpha(DERIVATE_CUTOFF, dt);
+        derivate = a_d * (values[i] - previous) / dt + (1.0 - a_d) * derivate;
+        let a = alpha(min_cutoff + beta * derivate.abs(), dt);
+        previous = a * values[i] + (1.0 - a) * previous;
+        result.push(previous);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHODS: [SmoothingMethod; 5] = [
+        SmoothingMethod::None,
+        SmoothingMethod::MovingAverage,
+        SmoothingMethod::SavitzkyGolay,
+        SmoothingMethod::Kalman,
+        SmoothingMethod::OneEuro,
+    ];
+
+    fn smoothing(method: SmoothingMethod) -> Smoothing {
+        Smoothing {
+            method,
+            window: 9,
+            process_noise: 100.0,
+            min_cutoff: 1.0,
+            beta: 0.0,
+        }
+    }
+
+    /// Sample times of `n` frames at 30 fps
+    fn time(n: usize) -> Vec<f64> {
+        (0..n).map(|i| i as f64 / 30.0).collect()
+    }
+
+    /// Uniform noise in [-amplitude, amplitude) from a linear congruential generator
+    fn noise(n: usize, amplitude: f64) -> Vec<f64> {
+        let mut state: u64 = 11;
+        (0..n)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                amplitude * ((state >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0)
+            })
+            .collect()
+    }
+
+    fn variance(values: &[f64]) -> f64 {
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64
+    }
+
+    #[test]
+    fn output_length_equals_input_length() {
+        for method in METHODS {
+            for n in [0, 1, 2, 5, 100] {
+                let values = noise(n, 10.0);
+                assert_eq!(smoothing(method).apply(&time(n), &values).len(), n);
+            }
+        }
+    }
+
+    #[test]
+    fn constant_signal_stays_constant() {
+        for method in METHODS {
+            let smoothed = smoothing(method).apply(&time(60), &[42.0; 60]);
+            assert!(
+                smoothed.iter().all(|v| (v - 42.0).abs() < 1e-6),
+                "{method:?} {smoothed:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn noise_variance_goes_down() {
+        let values = noise(300, 10.0)
+            .iter()
+            .map(|v| 100.0 + v)
+            .collect::<Vec<_>>();
+        let input = variance(&values);
+        for method in &METHODS[1..] {
+            let output = variance(&smoothing(*method).apply(&time(300), &values));
+            assert!(output < 0.5 * input, "{method:?} {output} {input}");
+        }
+    }
+
+    #[test]
+    fn savitzky_golay_keeps_a_parabola() {
+        let values = (0..20)
+            .map(|i| 0.5 * (i * i) as f64 - 3.0 * i as f64)
+            .collect::<Vec<_>>();
+        let smoothed = smoothing(SmoothingMethod::SavitzkyGolay).apply(&time(20), &values);
+        for (a, b) in values.iter().zip(smoothed) {
+            assert!((a - b).abs() < 1e-9, "{a} {b}");
+        }
+    }
+}
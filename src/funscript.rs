@@ -1,19 +1,191 @@
 use log::info;
+use log::warn;
 use serde::{Deserialize, Serialize};
+use std::io::Write;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Funscript JSON, can be imported by OpenFunscripter
+    Funscript,
+    /// OpenFunscripter project with the video path and the funscript (`.ofp`)
+    Ofp,
+    /// `time,pos` lines as used by Handy style uploaders (`.csv`)
+    Csv,
+    /// Per frame positions before the simplification as `at,pos` CSV with header (`.raw.csv`)
+    RawCsv,
+}
+
+impl OutputFormat {
+    /// Format for the extension of `file_path`, funscript for unknown extensions
+    pub fn from_path(file_path: &str) -> Self {
+        let file_name = std::path::Path::new(file_path)
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .map(|file_name| file_name.to_lowercase())
+            .unwrap_or_default();
+        if file_name.ends_with(".raw.csv") {
+            OutputFormat::RawCsv
+        } else if file_name.ends_with(".ofp") {
+            OutputFormat::Ofp
+        } else if file_name.ends_with(".csv") {
+            OutputFormat::Csv
+        } else {
+            OutputFormat::Funscript
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct FunscriptAction {
-    pos: u8,
-    at: u32,
+    #[serde(deserialize_with = "deserialize_pos")]
+    pub pos: u8,
+    #[serde(deserialize_with = "deserialize_at")]
+    pub at: u32,
+}
+
+// NOTE: some tools write float values
+fn deserialize_pos<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    Ok(f64::deserialize(deserializer)?
+        .round()
+        .clamp(0.0, u8::MAX as f64) as u8)
+}
+
+fn deserialize_at<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    Ok(f64::deserialize(deserializer)?
+        .round()
+        .clamp(0.0, u32::MAX as f64) as u32)
+}
+
+fn default_version() -> String {
+    "1.0".to_string()
+}
+
+fn default_range() -> u8 {
+    90
+}
+
+/// Time in the `HH:MM:SS.mmm` format used by the OpenFunscripter metadata
+fn format_time(time_in_ms: u32) -> String {
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        time_in_ms / 3_600_000,
+        time_in_ms / 60_000 % 60,
+        time_in_ms / 1000 % 60,
+        time_in_ms % 1000
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FunscriptChapter {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub start_time: String,
+    #[serde(default)]
+    pub end_time: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FunscriptBookmark {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub time: String,
+}
+
+/// Time range of the video covered by the generated actions
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackedSegment {
+    pub start_time: String,
+    pub end_time: String,
+}
+
+/// Tool and settings used to generate the script
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeneratorMetadata {
+    pub name: String,
+    pub version: String,
+    pub arguments: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segment: Option<TrackedSegment>,
+    /// Frames dropped by the forward-backward check, only with `--fb-check`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dropped_frames: Option<usize>,
+    /// Actions changed by `--max-speed` and `--min-interval`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed_limit: Option<crate::speed_limit::SpeedLimitReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct FunscriptMetadata {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub title: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub creator: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub performers: Vec<String>,
+    /// Video duration in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chapters: Vec<FunscriptChapter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bookmarks: Vec<FunscriptBookmark>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generator: Option<GeneratorMetadata>,
+    /// Metadata fields written by other tools, kept as they are
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FunscriptContent {
+    #[serde(default = "default_version")]
     version: String,
+    #[serde(default)]
     inverted: bool,
+    #[serde(default = "default_range")]
     range: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     fps: Option<f32>,
+    #[serde(default)]
     actions: Vec<FunscriptAction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<FunscriptMetadata>,
+    /// Fields written by other tools, kept as they are
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl FunscriptContent {
+    pub fn actions(&self) -> &[FunscriptAction] {
+        &self.actions
+    }
+}
+
+/// Script of an OpenFunscripter project, `path` is the funscript next to the project file
+#[derive(Serialize)]
+struct OfpScript<'a> {
+    path: String,
+    #[serde(flatten)]
+    content: &'a FunscriptContent,
+}
+
+/// OpenFunscripter project in the JSON project layout: the video path and the scripts
+/// opened with it. Older OpenFunscripter versions with binary project files can not open it,
+/// import the funscript output there.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OfpProject<'a> {
+    version: u32,
+    media_file: &'a str,
+    funscripts: Vec<OfpScript<'a>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -24,40 +196,523 @@ pub struct Funscript {
 }
 
 impl Funscript {
-    pub fn new(video_fps: f32, start_time_in_ms: f32, score: Vec<mint::Point2<i32>>) -> Self {
+    /// The positions of `score` are written as they are, an inverted score is stored with
+    /// inverted positions and `inverted: false` so players do not invert it again.
+    /// `offset_in_ms` shifts all actions, actions shifted before the video start are dropped.
+    pub fn new(
+        video_fps: f32,
+        start_time_in_ms: f32,
+        offset_in_ms: f32,
+        score: Vec<mint::Point2<f64>>,
+    ) -> Self {
         Self {
             video_fps,
             start_time_in_ms,
-            content: Funscript::to_funscript_content(score, video_fps, start_time_in_ms),
+            content: Funscript::to_funscript_content(
+                score,
+                video_fps,
+                start_time_in_ms + offset_in_ms,
+            ),
         }
     }
 
     fn to_funscript_content(
-        score: Vec<mint::Point2<i32>>,
+        score: Vec<mint::Point2<f64>>,
         video_fps: f32,
         start_time_in_ms: f32,
     ) -> FunscriptContent {
         let frame_time_in_ms = 1000.0 / video_fps;
+        let at = |a: &mint::Point2<f64>| start_time_in_ms + frame_time_in_ms * (a.x as f32);
+
+        let before_start = score.iter().filter(|a| at(a) < 0.0).count();
+        if before_start > 0 {
+            warn!("drop {before_start} actions the offset moves before the video start");
+        }
 
         FunscriptContent {
             version: "1.0".to_string(),
             inverted: false,
-            range: 90,
+            range: default_range(),
             fps: Some(video_fps),
+            metadata: None,
+            other: serde_json::Map::new(),
             actions: score
                 .iter()
+                .filter(|a| at(a) >= 0.0)
                 .map(|a| FunscriptAction {
-                    pos: a.y as u8,
-                    at: (start_time_in_ms + frame_time_in_ms * (a.x as f32)) as u32,
+                    pos: a.y.round().clamp(0.0, 100.0) as u8,
+                    at: at(a) as u32,
                 })
                 .collect(),
         }
     }
 
-    pub fn save(&mut self, file_path: &str) {
-        let serialized_funscript = serde_json::to_string(&self.content).unwrap();
-        info!("save funscript to {file_path}");
-        std::fs::write(file_path, serialized_funscript)
-            .unwrap_or_else(|_| panic!("Unable to write funscript: {file_path}"));
+    fn frame_to_ms(&self, frame: f64) -> u32 {
+        (self.start_time_in_ms + 1000.0 / self.video_fps * frame as f32).max(0.0) as u32
+    }
+
+    /// Record the tool version, the command line arguments, the time range of the
+    /// generated actions, the frames dropped by the forward-backward check and the actions
+    /// changed by the speed limit in the metadata
+    pub fn set_generator(
+        &mut self,
+        arguments: Vec<String>,
+        dropped_frames: Option<usize>,
+        speed_limit: Option<crate::speed_limit::SpeedLimitReport>,
+    ) {
+        let segment = match (self.content.actions.first(), self.content.actions.last()) {
+            (Some(first), Some(last)) => Some(TrackedSegment {
+                start_time: format_time(first.at),
+                end_time: format_time(last.at),
+            }),
+            _ => None,
+        };
+
+        self.content
+            .metadata
+            .get_or_insert_with(FunscriptMetadata::default)
+            .generator = Some(GeneratorMetadata {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            arguments,
+            segment,
+            dropped_frames,
+            speed_limit,
+        });
+    }
+
+    /// Add a bookmark at the given frame (relative to the start time)
+    pub fn add_bookmark(&mut self, name: &str, frame: f64) {
+        let time = format_time(self.frame_to_ms(frame));
+        self.content
+            .metadata
+            .get_or_insert_with(FunscriptMetadata::default)
+            .bookmarks
+            .push(FunscriptBookmark {
+                name: name.to_string(),
+                time,
+            });
+    }
+
+    /// Parse an existing funscript file
+    pub fn load(file_path: &str) -> Result<FunscriptContent, Box<dyn std::error::Error>> {
+        let file_content = std::fs::read_to_string(file_path)?;
+        Ok(serde_json::from_str(&file_content)?)
+    }
+
+    /// Insert the generated actions into `existing`. Existing actions in the time range of
+    /// the generated actions are replaced and actions less than one frame apart are merged,
+    /// the generated action wins. The header of `existing` (version, inverted, range, fps and
+    /// unknown fields) is kept, with `inverted: true` the generated positions are inverted so
+    /// they play like in a separate script. The range scales the whole script and is kept.
+    pub fn merge(&mut self, existing: FunscriptContent) {
+        let frame_time_in_ms = 1000.0 / self.video_fps;
+        let mut generated = std::mem::take(&mut self.content.actions);
+        let (Some(first), Some(last)) = (generated.first(), generated.last()) else {
+            self.content = existing;
+            return;
+        };
+        let start = first.at as f32;
+        let end = last.at as f32;
+
+        if existing.inverted {
+            info!("existing funscript is inverted, invert the generated positions");
+            for action in generated.iter_mut() {
+                action.pos = 100 - action.pos.min(100);
+            }
+        }
+
+        let existing_actions = existing.actions.len();
+        let mut actions = existing
+            .actions
+            .into_iter()
+            .filter(|a| (a.at as f32) < start || (a.at as f32) > end)
+            .map(|a| (a, false))
+            .collect::<Vec<_>>();
+        let generated_actions = generated.len();
+        actions.extend(generated.into_iter().map(|a| (a, true)));
+        actions.sort_by_key(|(a, generated)| (a.at, !*generated));
+
+        // merge an existing action less than one frame next to another action into it
+        let mut merged: Vec<(FunscriptAction, bool)> = Vec::with_capacity(actions.len());
+        for (action, generated) in actions {
+            match merged.last_mut() {
+                Some(last)
+                    if !(generated && last.1)
+                        && ((action.at - last.0.at) as f32) < frame_time_in_ms =>
+                {
+                    if generated {
+                        *last = (action, generated);
+                    }
+                }
+                _ => merged.push((action, generated)),
+            }
+        }
+        let kept = merged.iter().filter(|(_, generated)| !generated).count();
+        info!(
+            "merge {generated_actions} actions, replace {} existing actions",
+            existing_actions - kept
+        );
+        let actions = merged
+            .into_iter()
+            .map(|(action, _)| action)
+            .collect::<Vec<_>>();
+
+        // keep the existing metadata but describe the new generator run
+        let metadata = match (existing.metadata, self.content.metadata.take()) {
+            (Some(mut existing), Some(generated)) => {
+                existing.generator = generated.generator;
+                existing.bookmarks.extend(generated.bookmarks);
+                Some(existing)
+            }
+            (existing, generated) => existing.or(generated),
+        };
+
+        self.content = FunscriptContent {
+            actions,
+            metadata,
+            ..existing
+        };
+    }
+
+    /// Sort the actions, drop actions with duplicated `at` and clamp the positions to
+    /// 0..=100. Each repair is reported, returns the number of repaired actions.
+    pub fn validate(&mut self) -> usize {
+        let actions = &mut self.content.actions;
+        let mut repaired = 0;
+
+        let unsorted = actions.windows(2).filter(|a| a[0].at > a[1].at).count();
+        if unsorted > 0 {
+            warn!("sort {unsorted} funscript actions with decreasing timestamp");
+            actions.sort_by_key(|a| a.at);
+            repaired += unsorted;
+        }
+
+        let len = actions.len();
+        actions.dedup_by_key(|a| a.at);
+        if actions.len() < len {
+            warn!(
+                "drop {} actions with duplicated timestamp",
+                len - actions.len()
+            );
+            repaired += len - actions.len();
+        }
+
+        let out_of_range = actions.iter().filter(|a| a.pos > 100).count();
+        if out_of_range > 0 {
+            warn!("clamp {out_of_range} actions with a position above 100");
+            for action in actions.iter_mut().filter(|a| a.pos > 100) {
+                action.pos = 100;
+            }
+            repaired += out_of_range;
+        }
+
+        if repaired > 0 {
+            warn!("repaired {repaired} invalid funscript actions");
+        }
+
+        repaired
+    }
+
+    pub fn to_json(&self, pretty: bool) -> Result<String, serde_json::Error> {
+        if pretty {
+            serde_json::to_string_pretty(&self.content)
+        } else {
+            serde_json::to_string(&self.content)
+        }
+    }
+
+    /// OpenFunscripter project for `media_file` with the actions as its only script, the
+    /// script is named like the project file `file_path` with a `.funscript` extension
+    pub fn to_ofp(
+        &self,
+        file_path: &str,
+        media_file: &str,
+        pretty: bool,
+    ) -> Result<String, serde_json::Error> {
+        let script_path = std::path::Path::new(file_path).with_extension("funscript");
+        let project = OfpProject {
+            version: 1,
+            media_file,
+            funscripts: vec![OfpScript {
+                path: script_path.to_string_lossy().to_string(),
+                content: &self.content,
+            }],
+        };
+        if pretty {
+            serde_json::to_string_pretty(&project)
+        } else {
+            serde_json::to_string(&project)
+        }
+    }
+
+    /// Actions as `at,pos` lines, optional with a header line
+    pub fn to_csv(&self, header: bool) -> String {
+        let mut csv = String::new();
+        if header {
+            csv.push_str("at,pos\n");
+        }
+        for action in self.content.actions.iter() {
+            csv.push_str(format!("{},{}\n", action.at, action.pos).as_str());
+        }
+        csv
+    }
+
+    /// Validate the actions and write them in the given format, `media_file` is the video
+    /// of a project file. The file is replaced atomically, with `backup` the previous file is
+    /// kept as `<file_path>.bak`.
+    pub fn save(
+        &mut self,
+        file_path: &str,
+        media_file: &str,
+        format: OutputFormat,
+        pretty: bool,
+        backup: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.validate();
+
+        let content = match format {
+            OutputFormat::Funscript => self.to_json(pretty)?,
+            OutputFormat::Ofp => self.to_ofp(file_path, media_file, pretty)?,
+            OutputFormat::Csv => self.to_csv(false),
+            OutputFormat::RawCsv => self.to_csv(true),
+        };
+
+        info!("save {format:?} to {file_path}");
+        write_atomic(file_path, content.as_bytes(), backup)?;
+        Ok(())
+    }
+}
+
+/// Write into a temporary file next to `file_path` and rename it, so the target is never
+/// left half written
+fn write_atomic(file_path: &str, content: &[u8], backup: bool) -> std::io::Result<()> {
+    let path = std::path::Path::new(file_path);
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("output");
+    let temp_path = path.with_file_name(format!(".{file_name}.tmp"));
+
+    let result = (|| {
+        let mut file = std::fs::File::create(&temp_path)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+
+        if backup && path.exists() {
+            let backup_path = path.with_file_name(format!("{file_name}.bak"));
+            info!("backup {file_path} to {}", backup_path.display());
+            std::fs::copy(path, backup_path)?;
+        }
+
+        std::fs::rename(&temp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 25 fps, one frame is 40 ms
+    const FPS: f32 = 25.0;
+
+    fn generated(actions: &[(f64, f64)]) -> Funscript {
+        let score = actions
+            .iter()
+            .map(|(x, y)| mint::Point2 { x: *x, y: *y })
+            .collect();
+        Funscript::new(FPS, 0.0, 0.0, score)
+    }
+
+    fn existing(json: &str) -> FunscriptContent {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn actions(funscript: &Funscript) -> Vec<(u32, u8)> {
+        funscript
+            .content
+            .actions
+            .iter()
+            .map(|a| (a.at, a.pos))
+            .collect()
+    }
+
+    #[test]
+    fn merge_replaces_the_covered_range() {
+        // generated actions from 1520 to 3520 ms
+        let mut funscript = generated(&[(38.0, 10.0), (50.0, 90.0), (88.0, 20.0)]);
+        funscript.merge(existing(
+            r#"{"actions": [
+                {"at": 0, "pos": 0}, {"at": 1000, "pos": 100}, {"at": 2000, "pos": 0},
+                {"at": 3000, "pos": 100}, {"at": 4000, "pos": 0}, {"at": 5000, "pos": 100}
+            ]}"#,
+        ));
+        assert_eq!(
+            actions(&funscript),
+            [
+                (0, 0),
+                (1000, 100),
+                (1520, 10),
+                (2000, 90),
+                (3520, 20),
+                (4000, 0),
+                (5000, 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_deduplicates_actions_within_one_frame() {
+        let mut funscript = generated(&[(38.0, 10.0), (88.0, 20.0)]);
+        funscript.merge(existing(
+            r#"{"actions": [
+                {"at": 1400, "pos": 50}, {"at": 1490, "pos": 60}, {"at": 1520, "pos": 70},
+                {"at": 3550, "pos": 80}, {"at": 3555, "pos": 90}, {"at": 3600, "pos": 0}
+            ]}"#,
+        ));
+        // the generated actions win against existing actions less than 40 ms apart
+        assert_eq!(
+            actions(&funscript),
+            [(1400, 50), (1520, 10), (3520, 20), (3600, 0)]
+        );
+
+        // without generated actions the existing script is kept as it is
+        let mut funscript = generated(&[]);
+        funscript.merge(existing(
+            r#"{"actions": [{"at": 0, "pos": 0}, {"at": 10, "pos": 100}]}"#,
+        ));
+        assert_eq!(actions(&funscript), [(0, 0), (10, 100)]);
+    }
+
+    #[test]
+    fn merge_keeps_the_existing_header_and_metadata() {
+        let mut funscript = generated(&[(50.0, 10.0), (75.0, 80.0)]);
+        funscript.set_generator(vec!["mtfg-rs".to_string()], None, None);
+        funscript.add_bookmark("cut", 50.0);
+        funscript.merge(existing(
+            r#"{
+                "version": "1.1", "inverted": true, "range": 80, "fps": 30,
+                "custom": {"key": [1, 2]},
+                "actions": [{"at": 0, "pos": 0}],
+                "metadata": {
+                    "title": "title", "creator": "creator", "tool": "other",
+                    "bookmarks": [{"name": "start", "time": "00:00:00.000"}]
+                }
+            }"#,
+        ));
+
+        // the inverted existing script inverts the generated positions
+        assert_eq!(actions(&funscript), [(0, 0), (2000, 90), (3000, 20)]);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&funscript.to_json(false).unwrap()).unwrap();
+        assert_eq!(json["version"], "1.1");
+        assert_eq!(json["inverted"], true);
+        assert_eq!(json["range"], 80);
+        assert_eq!(json["fps"], 30.0);
+        assert_eq!(json["custom"], serde_json::json!({"key": [1, 2]}));
+        let metadata = &json["metadata"];
+        assert_eq!(metadata["title"], "title");
+        assert_eq!(metadata["creator"], "creator");
+        assert_eq!(metadata["tool"], "other");
+        assert_eq!(metadata["generator"]["arguments"][0], "mtfg-rs");
+        assert_eq!(
+            metadata["bookmarks"],
+            serde_json::json!([
+                {"name": "start", "time": "00:00:00.000"},
+                {"name": "cut", "time": "00:00:02.000"}
+            ])
+        );
+    }
+
+    #[test]
+    fn output_format_from_path() {
+        assert_eq!(
+            OutputFormat::from_path("a.funscript"),
+            OutputFormat::Funscript
+        );
+        assert_eq!(OutputFormat::from_path("a.json"), OutputFormat::Funscript);
+        assert_eq!(OutputFormat::from_path("a.CSV"), OutputFormat::Csv);
+        assert_eq!(OutputFormat::from_path("a.raw.csv"), OutputFormat::RawCsv);
+        assert_eq!(OutputFormat::from_path("dir/a.ofp"), OutputFormat::Ofp);
+    }
+
+    #[test]
+    fn formats_share_the_actions() {
+        let funscript = generated(&[(0.0, 0.0), (25.0, 100.0)]);
+        assert_eq!(funscript.to_csv(false), "0,0\n1000,100\n");
+        assert_eq!(funscript.to_csv(true), "at,pos\n0,0\n1000,100\n");
+
+        let project: serde_json::Value = serde_json::from_str(
+            &funscript
+                .to_ofp("dir/video.ofp", "dir/video.mp4", false)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(project["mediaFile"], "dir/video.mp4");
+        let script = &project["funscripts"][0];
+        assert_eq!(script["path"], "dir/video.funscript");
+        assert_eq!(
+            script["actions"],
+            serde_json::json!([{"pos": 0, "at": 0}, {"pos": 100, "at": 1000}])
+        );
+        assert_eq!(script["range"], 90);
+    }
+
+    #[test]
+    fn validate_repairs_actions() {
+        let mut funscript = generated(&[]);
+        funscript.content = existing(
+            r#"{"actions": [
+                {"at": 200, "pos": 150}, {"at": 100, "pos": 20}, {"at": 200, "pos": 30},
+                {"at": 0, "pos": -5}, {"at": 300, "pos": 100}
+            ]}"#,
+        );
+        // two unsorted pairs, one duplicated timestamp and one position above 100
+        assert_eq!(funscript.validate(), 4);
+        assert_eq!(
+            actions(&funscript),
+            [(0, 0), (100, 20), (200, 100), (300, 100)]
+        );
+        assert_eq!(funscript.validate(), 0);
+    }
+
+    #[test]
+    fn save_replaces_the_file_and_keeps_a_backup() {
+        let dir = tempdir::TempDir::new("mtfg-rs").unwrap();
+        let path = dir.path().join("script.csv");
+        let file_path = path.to_str().unwrap();
+        let backup_path = dir.path().join("script.csv.bak");
+        let temp_path = dir.path().join(".script.csv.tmp");
+
+        let mut funscript = generated(&[(0.0, 10.0)]);
+        funscript
+            .save(file_path, "", OutputFormat::Csv, false, true)
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0,10\n");
+        assert!(!backup_path.exists());
+
+        let mut funscript = generated(&[(0.0, 20.0)]);
+        funscript
+            .save(file_path, "", OutputFormat::Csv, false, true)
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0,20\n");
+        assert_eq!(std::fs::read_to_string(&backup_path).unwrap(), "0,10\n");
+        assert!(!temp_path.exists());
+
+        // the content goes through the temporary file, when it can not be written the
+        // output is not touched
+        std::fs::create_dir(&temp_path).unwrap();
+        let mut funscript = generated(&[(0.0, 30.0)]);
+        assert!(funscript
+            .save(file_path, "", OutputFormat::Csv, false, false)
+            .is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0,20\n");
     }
 }
@@ -1,34 +1,275 @@
-// use log::error;
+use log::warn;
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum InterpolationMethod {
+    /// Keep only the tracked frames
+    None,
+    Linear,
+    MonotonicCubic,
+    Akima,
+    CatmullRom,
+}
+
+/// Fill the frames skipped by `frame_step_size` between the first and last tracked frame
 pub fn interpolate_score(
-    raw_score: Vec<mint::Point2<i32>>,
+    raw_score: Vec<mint::Point2<f64>>,
     frame_step_size: u32,
-) -> Option<Vec<mint::Point2<i32>>> {
-    if raw_score.len() < 2 {
+    method: InterpolationMethod,
+) -> Option<Vec<mint::Point2<f64>>> {
+    if frame_step_size <= 1 || method == InterpolationMethod::None {
         return Some(raw_score);
     }
 
-    if frame_step_size <= 1 {
-        return Some(raw_score);
+    // splines require strictly increasing x values
+    let mut score: Vec<mint::Point2<f64>> = Vec::with_capacity(raw_score.len());
+    for item in raw_score {
+        match score.last() {
+            Some(last) if item.x <= last.x => {
+                warn!("drop score point with non increasing frame {}", item.x)
+            }
+            _ => score.push(item),
+        }
     }
 
-    let x = raw_score
-        .iter()
-        .map(|item| item.x as f64)
-        .collect::<Vec<_>>();
-    let y = raw_score
-        .iter()
-        .map(|item| item.y as f64)
-        .collect::<Vec<_>>();
+    if score.len() < 2 {
+        return Some(score);
+    }
 
-    let mut spline = mentat::MonotonicCubicSpline::new(&x, &y);
+    let x = score.iter().map(|item| item.x).collect::<Vec<_>>();
+    let y = score.iter().map(|item| item.y).collect::<Vec<_>>();
+
+    let first_frame = x.first()?.round() as i64;
+    let last_frame = x.last()?.round() as i64;
+
+    let mut interpolate: Box<dyn FnMut(f64) -> f64> = match method {
+        InterpolationMethod::None => return Some(score),
+        InterpolationMethod::Linear => Box::new(move |point| evaluate(&x, &y, None, point)),
+        InterpolationMethod::MonotonicCubic => {
+            let slopes = monotonic_slopes(&x, &y);
+            Box::new(move |point| evaluate(&x, &y, Some(&slopes), point))
+        }
+        InterpolationMethod::Akima => {
+            let slopes = akima_slopes(&x, &y);
+            Box::new(move |point| evaluate(&x, &y, Some(&slopes), point))
+        }
+        InterpolationMethod::CatmullRom => {
+            let slopes = catmull_rom_slopes(&x, &y);
+            Box::new(move |point| evaluate(&x, &y, Some(&slopes), point))
+        }
+    };
 
     Some(
-        (raw_score.first().unwrap().x..raw_score.first().unwrap().x + raw_score.len() as i32)
-            .map(|x| mint::Point2 {
-                x: x,
-                y: spline.interpolate(x.into()) as i32,
+        (first_frame..=last_frame)
+            .map(|frame| mint::Point2 {
+                x: frame as f64,
+                y: interpolate(frame as f64),
             })
             .collect::<Vec<_>>(),
     )
 }
+
+fn secants(x: &[f64], y: &[f64]) -> Vec<f64> {
+    x.windows(2)
+        .zip(y.windows(2))
+        .map(|(x, y)| (y[1] - y[0]) / (x[1] - x[0]))
+        .collect()
+}
+
+/// Fritsch-Carlson tangents of a monotonic cubic spline, which does not overshoot at
+/// turning points and in gaps of missing frames
+fn monotonic_slopes(x: &[f64], y: &[f64]) -> Vec<f64> {
+    let n = x.len();
+    let secants = secants(x, y);
+    let mut slopes = (0..n)
+        .map(|i| match i {
+            0 => secants[0],
+            i if i == n - 1 => secants[n - 2],
+            i if secants[i - 1] * secants[i] <= 0.0 => 0.0,
+            i => 0.5 * (secants[i - 1] + secants[i]),
+        })
+        .collect::<Vec<_>>();
+
+    for (i, secant) in secants.iter().enumerate() {
+        if *secant == 0.0 {
+            slopes[i] = 0.0;
+            slopes[i + 1] = 0.0;
+            continue;
+        }
+        let alpha = slopes[i] / secant;
+        let beta = slopes[i + 1] / secant;
+        let length = alpha.hypot(beta);
+        if length > 3.0 {
+            slopes[i] = 3.0 / length * alpha * secant;
+            slopes[i + 1] = 3.0 / length * beta * secant;
+        }
+    }
+
+    slopes
+}
+
+/// Tangents of a (cardinal) Catmull-Rom spline for non uniform x values
+fn catmull_rom_slopes(x: &[f64], y: &[f64]) -> Vec<f64> {
+    let n = x.len();
+    let secants = secants(x, y);
+    (0..n)
+        .map(|i| match i {
+            0 => secants[0],
+            i if i == n - 1 => secants[n - 2],
+            i => (y[i + 1] - y[i - 1]) / (x[i + 1] - x[i - 1]),
+        })
+        .collect()
+}
+
+/// Tangents of the Akima spline, which does not overshoot next to outliers
+fn akima_slopes(x: &[f64], y: &[f64]) -> Vec<f64> {
+    let n = x.len();
+    let secants = secants(x, y);
+    if n < 3 {
+        return vec![secants[0]; n];
+    }
+
+    // extend the secants by two values on each side as proposed by Akima
+    let mut m = Vec::with_capacity(n + 3);
+    m.push(3.0 * secants[0] - 2.0 * secants[1]);
+    m.push(2.0 * secants[0] - secants[1]);
+    m.extend_from_slice(&secants);
+    m.push(2.0 * secants[n - 2] - secants[n - 3]);
+    m.push(3.0 * secants[n - 2] - 2.0 * secants[n - 3]);
+
+    (0..n)
+        .map(|i| {
+            let w1 = (m[i + 3] - m[i + 2]).abs();
+            let w2 = (m[i + 1] - m[i]).abs();
+            if w1 + w2 < f64::EPSILON {
+                0.5 * (m[i + 1] + m[i + 2])
+            } else {
+                (w1 * m[i + 1] + w2 * m[i + 2]) / (w1 + w2)
+            }
+        })
+        .collect()
+}
+
+/// Evaluate the piecewise cubic hermite spline with the given slopes or the linear
+/// interpolation without slopes
+fn evaluate(x: &[f64], y: &[f64], slopes: Option<&[f64]>, point: f64) -> f64 {
+    let n = x.len();
+    if point <= x[0] {
+        return y[0];
+    }
+    if point >= x[n - 1] {
+        return y[n - 1];
+    }
+
+    let i = x.partition_point(|v| *v <= point) - 1;
+    let Some(slopes) = slopes else {
+        let t = (point - x[i]) / (x[i + 1] - x[i]);
+        return y[i] + (y[i + 1] - y[i]) * t;
+    };
+
+    mentat::MonotonicCubicSpline::hermite(
+        point,
+        (x[i], x[i + 1]),
+        (y[i], y[i + 1]),
+        (slopes[i], slopes[i + 1]),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHODS: [InterpolationMethod; 5] = [
+        InterpolationMethod::None,
+        InterpolationMethod::Linear,
+        InterpolationMethod::MonotonicCubic,
+        InterpolationMethod::Akima,
+        InterpolationMethod::CatmullRom,
+    ];
+
+    fn score(points: &[(f64, f64)]) -> Vec<mint::Point2<f64>> {
+        points
+            .iter()
+            .map(|(x, y)| mint::Point2 { x: *x, y: *y })
+            .collect()
+    }
+
+    #[test]
+    fn empty_and_single_sample() {
+        for method in METHODS {
+            assert_eq!(
+                interpolate_score(vec![], 3, method),
+                Some(vec![]),
+                "{method:?}"
+            );
+            let single = score(&[(3.0, 42.0)]);
+            assert_eq!(
+                interpolate_score(single.clone(), 3, method),
+                Some(single),
+                "{method:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn constant_signal() {
+        for method in METHODS.into_iter().skip(1) {
+            let result =
+                interpolate_score(score(&[(3.0, 50.0), (6.0, 50.0), (9.0, 50.0)]), 3, method)
+                    .unwrap();
+            assert_eq!(
+                result.iter().map(|p| p.x).collect::<Vec<_>>(),
+                [3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0],
+                "{method:?}"
+            );
+            assert!(
+                result.iter().all(|p| (p.y - 50.0).abs() < 1e-9),
+                "{method:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn missing_frames() {
+        // the frames 9 to 18 are missing, e.g. dropped boxes of the forward-backward check
+        let input = score(&[(3.0, 0.0), (6.0, 100.0), (21.0, 0.0), (24.0, 100.0)]);
+        for method in METHODS.into_iter().skip(1) {
+            let result = interpolate_score(input.clone(), 3, method).unwrap();
+            assert_eq!(result.len(), 22, "{method:?}");
+            assert!(
+                result.windows(2).all(|p| p[1].x - p[0].x == 1.0),
+                "{method:?}"
+            );
+            for p in input.iter() {
+                let value = result.iter().find(|r| r.x == p.x).unwrap().y;
+                assert!((value - p.y).abs() < 1e-9, "{method:?}: {value} at {}", p.x);
+            }
+            assert!(result.iter().all(|p| p.y.is_finite()), "{method:?}");
+        }
+
+        // the linear and monotonic interpolation do not overshoot in the gap
+        for method in [
+            InterpolationMethod::Linear,
+            InterpolationMethod::MonotonicCubic,
+        ] {
+            let result = interpolate_score(input.clone(), 3, method).unwrap();
+            assert!(
+                result.iter().all(|p| (-1e-9..=100.0 + 1e-9).contains(&p.y)),
+                "{method:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn non_increasing_frames() {
+        let input = score(&[
+            (3.0, 0.0),
+            (6.0, 50.0),
+            (6.0, 70.0),
+            (4.0, 10.0),
+            (9.0, 100.0),
+        ]);
+        let result = interpolate_score(input, 3, InterpolationMethod::Linear).unwrap();
+        assert_eq!(result.len(), 7);
+        assert_eq!(result[3].y, 50.0);
+    }
+}
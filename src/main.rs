@@ -1,54 +1,142 @@
 mod args;
+mod benchmark;
+mod chunks;
+mod evaluate;
 mod ffmpeg;
+mod filter;
 mod funscript;
 mod interpolate;
 mod logging;
+mod projection;
+mod roi_proposal;
+mod scene_cut;
 mod simplify;
+mod speed_limit;
+mod stats;
 mod tracker;
 mod trajectories;
 mod ui;
 
 use log::error;
+use log::info;
+use log::warn;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
 
 const WINDOW_NAME: &str = "mtfg-rs";
 const CHANNEL_CAPACITY: usize = 64;
+/// Number of frames the tracking preview can rewind
+const REWIND_FRAMES: usize = 32;
+/// Time to skip forward in the tracking preview
+const SKIP_SECONDS: f32 = 1.0;
+/// Number of frames shown in the score graph of the tracking preview
+const GRAPH_FRAMES: usize = 300;
 
 #[tokio::main(worker_threads = 6)]
 async fn main() {
+    if let Some(command) = args::parse_command() {
+        logging::setup_logging();
+        match command {
+            args::Command::Evaluate(evaluate_args) => {
+                if !evaluate::run(evaluate_args) {
+                    std::process::exit(1);
+                }
+            }
+            args::Command::Benchmark(benchmark_args) => {
+                if !benchmark::run(benchmark_args).await {
+                    std::process::exit(1);
+                }
+            }
+        }
+        return;
+    }
+
     let Some(mut args) = args::parse_args() else {
         return;
     };
 
     logging::setup_logging();
 
+    if args.stats {
+        stats::enable();
+    }
+
     let Ok(video_fps) = ffmpeg::get_video_fps(args.input.as_str()) else {
         error!("Could not determine video fps");
         return;
     };
 
-    let Ok(Some(preview_frame)) = ffmpeg::get_single_frame(args.input.as_str(), args.start_time as u32).await else {
-        error!("Failed to extract first frame");
-        return;
-    };
+    if args.headless {
+        args.video_filter = ui::Viewport::default().apply_to_filter(args.video_filter.as_str());
+    } else {
+        let Ok(Some(preview_frame)) = ffmpeg::get_single_frame(args.input.as_str(), args.start_time as u32).await else {
+            error!("Failed to extract first frame");
+            return;
+        };
+
+        args.video_filter =
+            ui::get_vr_viewport(WINDOW_NAME, &preview_frame.image, args.video_filter).await;
+    }
+
+    let mut proposals = vec![];
+    if args.propose_roi && args.roi.is_empty() {
+        proposals = roi_proposal::propose_rois(&args, video_fps, args.persons as usize).await;
+        if args.headless {
+            if proposals.len() < args.persons as usize {
+                error!("No ROI proposal with periodic motion found");
+                return;
+            }
+            args.roi = proposals.clone();
+        }
+    }
+
+    if args.chunks > 1 {
+        let start_time = std::time::Instant::now();
+        let Some(chunk) = chunks::track_chunks(&args, video_fps).await else {
+            error!("Chunked tracking FAILED");
+            return;
+        };
+        let tracking_duration = start_time.elapsed();
+        let frame_counter = chunk.frames.last().copied().unwrap_or(0);
+        let flagged_frames = chunk.flagged_frames();
+        let tracking_events = chunk
+            .scene_cut
+            .map(|frame| {
+                trajectories::TrackingEvent::new(frame, trajectories::TrackingEventKind::SceneCut)
+            })
+            .into_iter()
+            .collect();
 
-    args.video_filter =
-        ui::get_vr_viewport(WINDOW_NAME, &preview_frame.image, args.video_filter).await;
+        let mut tracking_result = trajectories::TrackingTrajectories::new(
+            args.frame_step_size,
+            args.persons as usize,
+            chunk.frames,
+            chunk.boxes,
+            tracking_events,
+        );
+        tracking_result.drop_frames(&flagged_frames, video_fps, args.start_time);
+        let dropped_frames = tracking_result.dropped_frames;
+
+        let success = create_funscript(&args, video_fps, tracking_result);
+        stats::report(
+            frame_counter * args.frame_step_size,
+            args.fb_check.then_some(dropped_frames),
+            tracking_duration,
+        );
+        if !success {
+            std::process::exit(1);
+        }
+        return;
+    }
 
     let mut frame_sender = vec![];
     let mut frame_receiver = vec![];
-    let mut tracking_sender = vec![];
-    let mut tracking_receiver = vec![];
 
     for _ in 0..args.persons {
         let (frame_tx, frame_rx) =
             tokio::sync::mpsc::channel::<ffmpeg::FFmpegFrame>(CHANNEL_CAPACITY);
         frame_sender.push(frame_tx);
         frame_receiver.push(frame_rx);
-
-        let (tracking_tx, tracking_rx) =
-            tokio::sync::mpsc::channel::<opencv::core::Rect>(CHANNEL_CAPACITY);
-        tracking_sender.push(tracking_tx);
-        tracking_receiver.push(tracking_rx);
     }
 
     let (frame_tx, mut frame_rx) =
@@ -66,78 +154,498 @@ async fn main() {
         return;
     };
 
-    let mut tracking_boxes = ui::get_rois(args.persons as usize, WINDOW_NAME, &mut frame).await;
+    let tracking_boxes = if args.roi.is_empty() {
+        ui::get_rois(args.persons as usize, WINDOW_NAME, &mut frame, &proposals).await
+    } else {
+        args.roi.clone()
+    };
 
-    while let Some(b) = tracking_boxes.pop() {
-        if let Some(r) = frame_receiver.pop() {
-            if let Some(p) = tracking_sender.pop() {
-                tokio::task::spawn_blocking(move || {
-                    tokio::runtime::Handle::current().block_on(tracker::track_feature(b, r, p));
-                });
-            } else {
-                error!("Not enough sender obj available");
-            }
-        } else {
-            error!("Not enough receiver obj available");
-        }
-    }
+    let settings = tracker::TrackerSettings {
+        kind: args.tracker,
+        fb_check: args.fb_check.then_some(tracker::ForwardBackwardCheck {
+            window: args.fb_window,
+            threshold: args.fb_threshold,
+        }),
+    };
+
+    // NOTE: a tracker can be up to CHANNEL_CAPACITY frames ahead of the preview
+    let mut trackers = tracking_boxes
+        .into_iter()
+        .zip(frame_receiver)
+        .enumerate()
+        .map(|(feature, (b, r))| {
+            tracker::TrackerHandle::spawn(
+                feature,
+                b,
+                r,
+                CHANNEL_CAPACITY,
+                REWIND_FRAMES + CHANNEL_CAPACITY + 2,
+                settings,
+            )
+        })
+        .collect::<Vec<_>>();
 
+    let skip_frames = ((video_fps * SKIP_SECONDS) as u32 / args.frame_step_size).max(1);
     let start_time = std::time::Instant::now();
     let mut frame_counter = 0;
+    let mut history: VecDeque<(u32, ffmpeg::FFmpegFrame)> = VecDeque::new();
+    let mut tracking_frames = vec![];
     let mut tracking_trajectories = vec![];
+    let mut tracking_events = vec![];
+    // (frame, feature) with a failed forward-backward check
+    let mut flagged_boxes: BTreeSet<(u32, usize)> = BTreeSet::new();
+    let mut score_history: VecDeque<mint::Point2<i32>> = VecDeque::new();
     while let Some(mut frame) = frame_rx.recv().await {
         frame_counter += 1;
 
         let mut result = vec![];
-        for item in tracking_receiver.iter_mut() {
-            let Some(tracking_box) = item.recv().await else {
+        for (feature, item) in trackers.iter_mut().enumerate() {
+            let Some((tracking_box, flagged)) = item.next_box(frame_counter).await else {
                 error!("Tracking box missing");
                 continue;
             };
+            if flagged {
+                flagged_boxes.insert((frame_counter, feature));
+            }
             result.push(tracking_box);
         }
 
-        let mut stop = false;
+        if frame.scene_cut {
+            tracking_events.push(trajectories::TrackingEvent::new(
+                frame_counter,
+                trajectories::TrackingEventKind::SceneCut,
+            ));
+            if args.scene_cut == scene_cut::SceneCutAction::Stop {
+                info!("scene cut at frame {frame_counter}, stop tracking");
+                break;
+            }
+
+            // the boxes of the trackers are meaningless after the cut
+            info!("scene cut at frame {frame_counter}, select new ROIs");
+            result = ui::get_rois(trackers.len(), WINDOW_NAME, &mut frame, &[]).await;
+            for (item, init_box) in trackers.iter_mut().zip(result.iter()) {
+                if !item.reinit(frame_counter, frame.clone(), *init_box).await {
+                    error!("Tracker not available");
+                }
+            }
+            for feature in 0..trackers.len() {
+                flagged_boxes.remove(&(frame_counter, feature));
+            }
+        }
+
+        history.push_back((frame_counter, frame.clone()));
+        if history.len() > REWIND_FRAMES {
+            history.pop_front();
+        }
+
+        if let Some(y) = trajectories::TrackingTrajectories::get_frame_y_diff(&result) {
+            score_history.push_back(mint::Point2 {
+                x: (frame_counter * args.frame_step_size) as i32,
+                y,
+            });
+            if score_history.len() > GRAPH_FRAMES {
+                score_history.pop_front();
+            }
+        }
 
-        if ((frame_counter - 1) % args.preview_frames) == 0 {
-            let fps = (args.frame_step_size * frame_counter * 1000) as u128
-                / start_time.elapsed().as_millis();
+        tracking_frames.push(frame_counter);
+        tracking_trajectories.push(result.clone());
+
+        if args.headless || ((frame_counter - 1) % args.preview_frames) != 0 {
+            continue;
+        }
 
-            stop = ui::preview_tracking_boxes(
-                WINDOW_NAME,
-                &mut frame,
-                &result,
-                format!("{fps} fps").as_str(),
+        let graph_score = if score_history.len() > 1 {
+            let (lower, upper) = position_range(&args);
+            trajectories::TrackingTrajectories::scale_y(
+                score_history.iter().copied().collect(),
+                Some(lower),
+                Some(upper),
+            )
+        } else {
+            vec![]
+        };
+        let graph_actions = simplify::simplify(
+            graph_score.clone(),
+            args.simplify,
+            args.epsilon,
+            args.prominence,
+            args.min_area,
+        );
+        let graph = ui::ScoreGraph {
+            score: &graph_score,
+            actions: &graph_actions,
+        };
+
+        let fps = (args.frame_step_size * frame_counter) as f64
+            / start_time.elapsed().as_secs_f64().max(0.001);
+
+        let preview_start = std::time::Instant::now();
+        let action = ui::preview_tracking_boxes(
+            WINDOW_NAME,
+            &frame,
+            &result,
+            graph,
+            format!("{fps:.0} fps").as_str(),
+            false,
+        )
+        .await;
+        stats::record("preview", preview_start);
+
+        let (action, action_frame) = if action == ui::PreviewAction::TogglePause {
+            info!("tracking paused at frame {frame_counter}");
+            tracking_events.push(trajectories::TrackingEvent::new(
+                frame_counter,
+                trajectories::TrackingEventKind::Pause,
+            ));
+            let (action, action_frame) = pause_tracking(
+                &history,
+                graph,
+                &tracking_frames,
+                &tracking_trajectories,
+                &mut tracking_events,
             )
             .await;
-        }
+            tracking_events.push(trajectories::TrackingEvent::new(
+                frame_counter,
+                trajectories::TrackingEventKind::Resume,
+            ));
+            (action, action_frame)
+        } else {
+            (action, frame_counter)
+        };
+
+        match action {
+            ui::PreviewAction::Stop => break,
+            ui::PreviewAction::Cut => {
+                info!("mark cut at frame {action_frame}");
+                tracking_events.push(trajectories::TrackingEvent::new(
+                    action_frame,
+                    trajectories::TrackingEventKind::Cut,
+                ));
+            }
+            ui::PreviewAction::Reselect(feature) if feature < trackers.len() => {
+                let Some((_, reselect_frame)) = history.iter().find(|(n, _)| *n == action_frame) else {
+                    error!("Frame {action_frame} no longer available");
+                    continue;
+                };
+                let mut reselect_frame = reselect_frame.clone();
+                let Some(init_box) = ui::get_rois(1, WINDOW_NAME, &mut reselect_frame, &[]).await.pop() else {
+                    continue;
+                };
 
-        tracking_trajectories.push(result);
+                info!("re-select feature {} at frame {action_frame}", feature + 1);
+                tracking_events.push(trajectories::TrackingEvent::new(
+                    action_frame,
+                    trajectories::TrackingEventKind::Reselect(feature),
+                ));
+                if !trackers[feature]
+                    .reinit(action_frame, reselect_frame, init_box)
+                    .await
+                {
+                    error!("Tracker {} not available", feature + 1);
+                    break;
+                }
 
-        if stop {
-            break;
+                // replace the boxes we already received from the drifted tracker
+                if let Ok(idx) = tracking_frames.binary_search(&action_frame) {
+                    if let Some(b) = tracking_trajectories[idx].get_mut(feature) {
+                        *b = init_box;
+                    }
+                }
+                flagged_boxes.remove(&(action_frame, feature));
+                for n in (action_frame + 1)..=frame_counter {
+                    let Some((tracking_box, flagged)) = trackers[feature].next_box(n).await else {
+                        error!("Tracking box missing");
+                        break;
+                    };
+                    if flagged {
+                        flagged_boxes.insert((n, feature));
+                    } else {
+                        flagged_boxes.remove(&(n, feature));
+                    }
+                    if let Ok(idx) = tracking_frames.binary_search(&n) {
+                        if let Some(b) = tracking_trajectories[idx].get_mut(feature) {
+                            *b = tracking_box;
+                        }
+                    }
+                }
+            }
+            ui::PreviewAction::Skip => {
+                let target_frame = frame_counter + skip_frames;
+                while frame_counter < target_frame {
+                    // keep the trackers running, all boxes get replaced after the skip
+                    for item in trackers.iter_mut() {
+                        item.drop_pending();
+                    }
+                    let Some(next_frame) = frame_rx.recv().await else {
+                        break;
+                    };
+                    frame = next_frame;
+                    frame_counter += 1;
+                }
+
+                info!("skip to frame {frame_counter}");
+                tracking_events.push(trajectories::TrackingEvent::new(
+                    frame_counter,
+                    trajectories::TrackingEventKind::Skip,
+                ));
+                history.clear();
+                history.push_back((frame_counter, frame.clone()));
+
+                let init_boxes = ui::get_rois(trackers.len(), WINDOW_NAME, &mut frame, &[]).await;
+                for (item, init_box) in trackers.iter_mut().zip(init_boxes.iter()) {
+                    if !item.reinit(frame_counter, frame.clone(), *init_box).await {
+                        error!("Tracker not available");
+                    }
+                }
+                tracking_frames.push(frame_counter);
+                tracking_trajectories.push(init_boxes);
+            }
+            _ => {}
         }
     }
 
+    let tracking_duration = start_time.elapsed();
+    info!("tracking events: {:?}", tracking_events);
+
     let mut tracking_result = trajectories::TrackingTrajectories::new(
         args.frame_step_size,
         args.persons as usize,
+        tracking_frames,
         tracking_trajectories,
+        tracking_events,
     );
+    let mut flagged_frames = flagged_boxes
+        .iter()
+        .map(|(frame, _)| *frame)
+        .collect::<Vec<_>>();
+    flagged_frames.dedup();
+    tracking_result.drop_frames(&flagged_frames, video_fps, args.start_time);
+    let dropped_frames = tracking_result.dropped_frames;
+
+    let success = create_funscript(&args, video_fps, tracking_result);
 
-    let raw_score = trajectories::TrackingTrajectories::scale_y(
+    stats::report(
+        frame_counter * args.frame_step_size,
+        args.fb_check.then_some(dropped_frames),
+        tracking_duration,
+    );
+    if !success {
+        std::process::exit(1);
+    }
+}
+
+/// Post-process the tracking result and write the output file, returns false when no
+/// output was written
+fn create_funscript(
+    args: &args::Args,
+    video_fps: f32,
+    mut tracking_result: trajectories::TrackingTrajectories,
+) -> bool {
+    let stage_start = std::time::Instant::now();
+    filter::Smoothing {
+        method: args.smoothing,
+        window: args.smoothing_window,
+        process_noise: args.kalman_process_noise,
+        min_cutoff: args.one_euro_min_cutoff,
+        beta: args.one_euro_beta,
+    }
+    .smooth_trajectories(&mut tracking_result, video_fps);
+    stats::record("post-processing smoothing", stage_start);
+
+    let stage_start = std::time::Instant::now();
+    let y_diff = trajectories::TrackingTrajectories::detrend_y(
         tracking_result.get_y_diff(),
-        Some(100),
-        Some(0),
+        args.detrend,
+        (args.detrend_window * video_fps as f64).round() as u32,
+        args.detrend_degree,
+    );
+    stats::record("post-processing detrend", stage_start);
+
+    let stage_start = std::time::Instant::now();
+    let (lower, upper) = position_range(args);
+    let raw_score = trajectories::TrackingTrajectories::normalize_y(
+        y_diff,
+        args.normalize,
+        (args.normalize_window * video_fps as f64).round() as u32,
+        args.clip_percentile,
+        Some(lower),
+        Some(upper),
     );
+    stats::record("post-processing normalize", stage_start);
 
-    let Some(interpolated_score) = interpolate::interpolate_score(raw_score, args.frame_step_size) else {
+    let stage_start = std::time::Instant::now();
+    let Some(interpolated_score) =
+        interpolate::interpolate_score(raw_score, args.frame_step_size, args.interpolation)
+    else {
         error!("Create funscript FAILED");
-        return;
+        return false;
     };
+    stats::record("post-processing interpolate", stage_start);
 
-    let score = simplify::rdp(interpolated_score, args.epsilon);
+    let format = args
+        .format
+        .unwrap_or_else(|| funscript::OutputFormat::from_path(args.output.as_str()));
+    let raw_signal = if format == funscript::OutputFormat::RawCsv {
+        Some(interpolated_score.clone())
+    } else {
+        None
+    };
 
-    let mut funscript = funscript::Funscript::new(video_fps, args.start_time, score);
-    funscript.save(args.output.as_str());
+    let stage_start = std::time::Instant::now();
+    let score = simplify::simplify(
+        interpolated_score,
+        args.simplify,
+        args.epsilon,
+        args.prominence,
+        args.min_area,
+    );
+
+    let score = match args.max_actions {
+        Some(max_actions) if score.len() > max_actions => {
+            info!("reduce {} actions to {max_actions}", score.len());
+            simplify::limit_actions(score, max_actions)
+        }
+        _ => score,
+    };
+
+    let score = match args.max_actions_per_second {
+        Some(rate) => {
+            let actions = score.len();
+            let score = simplify::limit_rate(score, video_fps, rate);
+            if score.len() < actions {
+                info!(
+                    "reduce {actions} actions to {} for {rate} actions per second",
+                    score.len()
+                );
+            }
+            score
+        }
+        None => score,
+    };
+    stats::record("post-processing simplify", stage_start);
+
+    let stage_start = std::time::Instant::now();
+    let (score, speed_limit) = if args.max_speed.is_some() || args.min_interval.is_some() {
+        let (score, report) =
+            speed_limit::limit_speed(score, video_fps, args.max_speed, args.min_interval);
+        info!(
+            "speed limit: merged {} and clamped {} actions",
+            report.merged, report.clamped
+        );
+        (score, Some(report))
+    } else {
+        (score, None)
+    };
+    stats::record("post-processing speed limit", stage_start);
+
+    if score.len() < 2 {
+        warn!("funscript contains only {} actions", score.len());
+    }
+
+    let mut funscript = funscript::Funscript::new(
+        video_fps,
+        args.start_time,
+        args.offset,
+        raw_signal.unwrap_or(score),
+    );
+    funscript.set_generator(
+        std::env::args().collect(),
+        args.fb_check.then_some(tracking_result.dropped_frames),
+        speed_limit,
+    );
+    for event in tracking_result.events.iter() {
+        let name = match event.kind {
+            trajectories::TrackingEventKind::Cut => "cut",
+            trajectories::TrackingEventKind::SceneCut => "scene cut",
+            _ => continue,
+        };
+        funscript.add_bookmark(name, (event.frame * args.frame_step_size) as f64);
+    }
+
+    if args.merge && format != funscript::OutputFormat::Funscript {
+        warn!("merge is only supported for the funscript format");
+    } else if args.merge && std::path::Path::new(args.output.as_str()).exists() {
+        match funscript::Funscript::load(args.output.as_str()) {
+            Ok(existing) => funscript.merge(existing),
+            Err(err) => {
+                error!("Could not parse existing funscript {}: {err}", args.output);
+                return false;
+            }
+        }
+    }
+
+    let stage_start = std::time::Instant::now();
+    let result = funscript.save(
+        args.output.as_str(),
+        args.input.as_str(),
+        format,
+        args.pretty,
+        args.backup,
+    );
+    stats::record("save", stage_start);
+    if let Err(err) = result {
+        error!("Unable to write {}: {err}", args.output);
+        return false;
+    }
+
+    true
+}
+
+/// Score values for the lowest and highest y difference. NOTE: by default a higher box
+/// (smaller y value) is a higher position.
+fn position_range(args: &args::Args) -> (i32, i32) {
+    if args.invert {
+        (args.min_position as i32, args.max_position as i32)
+    } else {
+        (args.max_position as i32, args.min_position as i32)
+    }
+}
+
+/// Show buffered frames until the user resume or request an action, returns the action
+/// together with the frame number it refers to
+async fn pause_tracking(
+    history: &VecDeque<(u32, ffmpeg::FFmpegFrame<'static>)>,
+    graph: ui::ScoreGraph<'_>,
+    tracking_frames: &[u32],
+    tracking_trajectories: &[Vec<opencv::core::Rect>],
+    tracking_events: &mut Vec<trajectories::TrackingEvent>,
+) -> (ui::PreviewAction, u32) {
+    let mut position = history.len().saturating_sub(1);
+    loop {
+        let Some((frame_number, frame)) = history.get(position) else {
+            return (ui::PreviewAction::Continue, 0);
+        };
+
+        let boxes = match tracking_frames.binary_search(frame_number) {
+            Ok(idx) => tracking_trajectories[idx].clone(),
+            Err(_) => vec![],
+        };
+
+        let action = ui::preview_tracking_boxes(
+            WINDOW_NAME,
+            frame,
+            &boxes,
+            graph,
+            format!("paused at frame {frame_number}").as_str(),
+            true,
+        )
+        .await;
+
+        match action {
+            ui::PreviewAction::Continue => {}
+            ui::PreviewAction::Rewind => position = position.saturating_sub(1),
+            ui::PreviewAction::Forward => position = (position + 1).min(history.len() - 1),
+            ui::PreviewAction::Cut => {
+                info!("mark cut at frame {frame_number}");
+                tracking_events.push(trajectories::TrackingEvent::new(
+                    *frame_number,
+                    trajectories::TrackingEventKind::Cut,
+                ));
+            }
+            ui::PreviewAction::TogglePause => return (ui::PreviewAction::Continue, *frame_number),
+            _ => return (action, *frame_number),
+        }
+    }
 }
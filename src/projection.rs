@@ -0,0 +1,406 @@
+use crate::ffmpeg::Dimensions;
+use crate::ffmpeg::FrameBuffer;
+use image::DynamicImage;
+use std::collections::HashMap;
+use std::f32::consts::FRAC_PI_2;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Number of remap tables kept in memory, the least recently used table is dropped first
+const CACHE_SIZE: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum InputProjection {
+    Equirect,
+    HalfEquirect,
+    Fisheye,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Stereo {
+    Mono,
+    SideBySide,
+    TopBottom,
+}
+
+/// Subset of the ffmpeg v360 filter parameters we can reproject in process
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct V360Params {
+    input: InputProjection,
+    in_stereo: Stereo,
+    yaw: f32,
+    pitch: f32,
+    roll: f32,
+    h_fov: f32,
+    v_fov: f32,
+    ih_fov: f32,
+    iv_fov: f32,
+    output: Dimensions,
+}
+
+impl V360Params {
+    /// Parse a single `v360` filter with flat output, `None` if the filter use any
+    /// option we do not implement (the caller should fallback to ffmpeg then)
+    pub fn parse(video_filter: &str) -> Option<Self> {
+        let options = video_filter.trim().strip_prefix("v360=")?;
+        if options.contains(',') || options.contains(';') {
+            return None;
+        }
+
+        let mut input = None;
+        let mut flat_output = false;
+        let mut in_stereo = Stereo::Mono;
+        let (mut yaw, mut pitch, mut roll): (f32, f32, f32) = (0.0, 0.0, 0.0);
+        let (mut h_fov, mut v_fov, mut d_fov): (f32, f32, f32) = (90.0, 45.0, 0.0);
+        let (mut ih_fov, mut iv_fov): (f32, f32) = (180.0, 180.0);
+        let (mut width, mut height) = (None, None);
+
+        for (idx, option) in options.split(':').enumerate() {
+            let (key, value) = match option.split_once('=') {
+                Some(kv) => kv,
+                // ffmpeg allows the first two options (input and output) without name
+                None if idx == 0 => ("input", option),
+                None if idx == 1 => ("output", option),
+                None => return None,
+            };
+
+            match key {
+                "input" => {
+                    input = Some(match value {
+                        "e" | "equirect" => InputProjection::Equirect,
+                        "he" | "hequirect" => InputProjection::HalfEquirect,
+                        "fisheye" => InputProjection::Fisheye,
+                        _ => return None,
+                    })
+                }
+                "output" => match value {
+                    "flat" | "rectilinear" | "gnomonic" => flat_output = true,
+                    _ => return None,
+                },
+                "in_stereo" => {
+                    in_stereo = match value {
+                        "2d" => Stereo::Mono,
+                        "sbs" => Stereo::SideBySide,
+                        "tb" => Stereo::TopBottom,
+                        _ => return None,
+                    }
+                }
+                "out_stereo" if value == "2d" => {}
+                "interp" if value == "line" || value == "linear" => {}
+                "yaw" => yaw = value.parse().ok()?,
+                "pitch" => pitch = value.parse().ok()?,
+                "roll" => roll = value.parse().ok()?,
+                "h_fov" => h_fov = value.parse().ok()?,
+                "v_fov" => v_fov = value.parse().ok()?,
+                "d_fov" => d_fov = value.parse().ok()?,
+                "ih_fov" => ih_fov = value.parse().ok()?,
+                "iv_fov" => iv_fov = value.parse().ok()?,
+                "w" => width = Some(value.parse::<u32>().ok()?),
+                "h" => height = Some(value.parse::<u32>().ok()?),
+                _ => return None,
+            }
+        }
+
+        if !flat_output {
+            return None;
+        }
+
+        let output = Dimensions::new(width?, height?);
+        if output.width == 0 || output.height == 0 {
+            return None;
+        }
+
+        if d_fov > 0.0 {
+            // same as fov_from_dfov() in ffmpeg for flat output
+            let da = (0.5 * d_fov.min(359.0)).to_radians().tan();
+            let d = (output.width as f32).hypot(output.height as f32);
+            h_fov = (da * output.width as f32).atan2(d).to_degrees() * 2.0;
+            v_fov = (da * output.height as f32).atan2(d).to_degrees() * 2.0;
+        }
+
+        Some(Self {
+            input: input?,
+            in_stereo,
+            yaw,
+            pitch,
+            roll,
+            h_fov,
+            v_fov,
+            ih_fov,
+            iv_fov,
+            output,
+        })
+    }
+}
+
+type Quaternion = [f32; 4];
+
+fn multiply_quaternion(a: &Quaternion, b: &Quaternion) -> Quaternion {
+    [
+        a[0] * b[0] - a[1] * b[1] - a[2] * b[2] - a[3] * b[3],
+        a[1] * b[0] + a[0] * b[1] + a[2] * b[3] - a[3] * b[2],
+        a[2] * b[0] + a[0] * b[2] + a[3] * b[1] - a[1] * b[3],
+        a[3] * b[0] + a[0] * b[3] + a[1] * b[2] - a[2] * b[1],
+    ]
+}
+
+/// Rotation in the default v360 rotation order yaw, pitch, roll
+fn rotation_quaternion(yaw: f32, pitch: f32, roll: f32) -> [Quaternion; 2] {
+    let (sin_yaw, cos_yaw) = (0.5 * yaw.to_radians()).sin_cos();
+    let (sin_pitch, cos_pitch) = (0.5 * pitch.to_radians()).sin_cos();
+    let (sin_roll, cos_roll) = (0.5 * roll.to_radians()).sin_cos();
+
+    let rotation = multiply_quaternion(
+        &multiply_quaternion(&[cos_yaw, 0.0, sin_yaw, 0.0], &[cos_pitch, sin_pitch, 0.0, 0.0]),
+        &[cos_roll, 0.0, 0.0, sin_roll],
+    );
+    let conjugate = [rotation[0], -rotation[1], -rotation[2], -rotation[3]];
+
+    [rotation, conjugate]
+}
+
+fn rotate(rotation: &[Quaternion; 2], vec: [f32; 3]) -> [f32; 3] {
+    let rotated = multiply_quaternion(
+        &multiply_quaternion(&rotation[0], &[0.0, vec[0], vec[1], vec[2]]),
+        &rotation[1],
+    );
+    [rotated[1], rotated[2], rotated[3]]
+}
+
+fn normalize(vec: [f32; 3]) -> [f32; 3] {
+    let norm = (vec[0] * vec[0] + vec[1] * vec[1] + vec[2] * vec[2]).sqrt();
+    [vec[0] / norm, vec[1] / norm, vec[2] / norm]
+}
+
+/// Map normalized [-1, 1] coordinate to pixel position like `scale()` in ffmpeg
+fn scale(x: f32, size: u32) -> f32 {
+    (0.5 * x + 0.5) * (size as f32 - 1.0)
+}
+
+/// Source pixel position for every output pixel
+pub struct RemapTable {
+    output: Dimensions,
+    input: Dimensions,
+    map: Vec<Option<[f32; 2]>>,
+}
+
+impl RemapTable {
+    pub fn new(params: &V360Params, input: Dimensions) -> Self {
+        let (eye_width, eye_height) = match params.in_stereo {
+            Stereo::Mono => (input.width, input.height),
+            Stereo::SideBySide => (input.width / 2, input.height),
+            Stereo::TopBottom => (input.width, input.height / 2),
+        };
+        let rotation = rotation_quaternion(params.yaw, params.pitch, params.roll);
+        let flat_range = [
+            (0.5 * params.h_fov).to_radians().tan(),
+            (0.5 * params.v_fov).to_radians().tan(),
+        ];
+        let fisheye_range = [params.ih_fov / 360.0, params.iv_fov / 360.0];
+
+        let mut map = Vec::with_capacity((params.output.width * params.output.height) as usize);
+        for j in 0..params.output.height {
+            for i in 0..params.output.width {
+                let l_x =
+                    flat_range[0] * ((2 * i + 1) as f32 / params.output.width as f32 - 1.0);
+                let l_y =
+                    flat_range[1] * ((2 * j + 1) as f32 / params.output.height as f32 - 1.0);
+                let vec = normalize(rotate(&rotation, normalize([l_x, l_y, 1.0])));
+
+                let position = match params.input {
+                    InputProjection::Equirect => {
+                        let phi = vec[0].atan2(vec[2]);
+                        let theta = vec[1].clamp(-1.0, 1.0).asin();
+                        Some([scale(phi / PI, eye_width), scale(theta / FRAC_PI_2, eye_height)])
+                    }
+                    InputProjection::HalfEquirect => {
+                        let phi = vec[0].atan2(vec[2]);
+                        let theta = vec[1].clamp(-1.0, 1.0).asin();
+                        if (-FRAC_PI_2..=FRAC_PI_2).contains(&phi) {
+                            Some([
+                                scale(phi / FRAC_PI_2, eye_width),
+                                scale(theta / FRAC_PI_2, eye_height),
+                            ])
+                        } else {
+                            None
+                        }
+                    }
+                    InputProjection::Fisheye => {
+                        let h = vec[0].hypot(vec[1]);
+                        let lh = if h > 0.0 { h } else { 1.0 };
+                        let phi = h.atan2(vec[2]) / PI;
+                        let uf = vec[0] / lh * phi / fisheye_range[0];
+                        let vf = vec[1] / lh * phi / fisheye_range[1];
+                        if uf.hypot(vf) <= 0.5 {
+                            Some([scale(2.0 * uf, eye_width), scale(2.0 * vf, eye_height)])
+                        } else {
+                            None
+                        }
+                    }
+                };
+
+                // NOTE: the output of the filter use only the first (left or top) eye
+                map.push(position);
+            }
+        }
+
+        Self {
+            output: params.output,
+            input: Dimensions::new(eye_width, eye_height),
+            map,
+        }
+    }
+
+    /// Bilinear sampling of the input frame, invisible areas stay black
+    pub fn apply(&self, frame: &FrameBuffer) -> FrameBuffer {
+        let stride = frame.width() as usize * 3;
+        let src = frame.as_raw();
+        let max_x = self.input.width.min(frame.width()).saturating_sub(1) as usize;
+        let max_y = self.input.height.min(frame.height()).saturating_sub(1) as usize;
+        let mut dst = vec![0u8; (self.output.width * self.output.height * 3) as usize];
+
+        for (pixel, position) in dst.chunks_exact_mut(3).zip(self.map.iter()) {
+            let Some([u, v]) = position else {
+                continue;
+            };
+            let u = u.clamp(0.0, max_x as f32);
+            let v = v.clamp(0.0, max_y as f32);
+            let (x0, y0) = (u as usize, v as usize);
+            let (x1, y1) = ((x0 + 1).min(max_x), (y0 + 1).min(max_y));
+            let (fx, fy) = (u - x0 as f32, v - y0 as f32);
+
+            for (channel, value) in pixel.iter_mut().enumerate() {
+                let p00 = src[y0 * stride + x0 * 3 + channel] as f32;
+                let p01 = src[y0 * stride + x1 * 3 + channel] as f32;
+                let p10 = src[y1 * stride + x0 * 3 + channel] as f32;
+                let p11 = src[y1 * stride + x1 * 3 + channel] as f32;
+                let top = p00 + (p01 - p00) * fx;
+                let bottom = p10 + (p11 - p10) * fx;
+                *value = (top + (bottom - top) * fy).round() as u8;
+            }
+        }
+
+        FrameBuffer::from_raw(self.output.width, self.output.height, dst)
+            .expect("reprojection: invalid output dimensions")
+    }
+}
+
+/// In process replacement for `ffmpeg::transform_frame` with v360 filters, remap tables
+/// are cached per filter so moving back and forth in the viewport selection is cheap
+#[derive(Default)]
+pub struct Reprojection {
+    /// remap table with the time of the last use
+    tables: HashMap<(String, u32, u32), (Arc<RemapTable>, u64)>,
+    clock: u64,
+}
+
+impl Reprojection {
+    pub fn transform_frame(
+        &mut self,
+        frame: &DynamicImage,
+        video_filter: &str,
+    ) -> Option<FrameBuffer> {
+        let frame = frame.as_rgb8()?;
+        let key = (video_filter.to_string(), frame.width(), frame.height());
+        self.clock += 1;
+
+        let table = match self.tables.get_mut(&key) {
+            Some((table, last_used)) => {
+                *last_used = self.clock;
+                table.clone()
+            }
+            None => {
+                let params = V360Params::parse(video_filter)?;
+                let table = Arc::new(RemapTable::new(
+                    &params,
+                    Dimensions::new(frame.width(), frame.height()),
+                ));
+                if self.tables.len() >= CACHE_SIZE {
+                    let oldest = self
+                        .tables
+                        .iter()
+                        .min_by_key(|(_, (_, last_used))| *last_used)
+                        .map(|(key, _)| key.clone());
+                    if let Some(oldest) = oldest {
+                        self.tables.remove(&oldest);
+                    }
+                }
+                self.tables.insert(key, (table.clone(), self.clock));
+                table
+            }
+        };
+
+        let start = std::time::Instant::now();
+        let result = table.apply(frame);
+        crate::stats::record("reprojection", start);
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Side by side frame with a smooth color pattern, so the difference of the bilinear
+    /// sampling in ffmpeg and in `RemapTable` stays small where the geometry matches
+    fn synthetic_frame(width: u32, height: u32) -> FrameBuffer {
+        FrameBuffer::from_fn(width, height, |x, y| {
+            let (x, y) = (x as f32, y as f32);
+            image::Rgb([
+                (127.5 + 120.0 * (x / 37.0).sin() * (y / 53.0).cos()) as u8,
+                (127.5 + 120.0 * (x / 71.0 + y / 29.0).sin()) as u8,
+                (127.5 + 120.0 * (y / 41.0).sin()) as u8,
+            ])
+        })
+    }
+
+    #[tokio::test]
+    #[ignore = "requires ffmpeg"]
+    async fn remap_table_matches_ffmpeg_v360() {
+        let frame = DynamicImage::ImageRgb8(synthetic_frame(1600, 800));
+        let mut reprojection = Reprojection::default();
+
+        for (yaw, pitch, roll, fov) in [
+            (0, 0, 0, 90),
+            (30, -20, 0, 90),
+            (-45, -60, 15, 110),
+            (60, 25, -30, 70),
+            (0, -80, 90, 100),
+        ] {
+            let video_filter = crate::args::DEFAULT_VIDEO_FILTER
+                .replace("{yaw}", yaw.to_string().as_str())
+                .replace("{pitch}", pitch.to_string().as_str())
+                .replace("{roll}", roll.to_string().as_str())
+                .replace("{fov}", fov.to_string().as_str());
+
+            let expected = crate::ffmpeg::transform_frame(&frame, video_filter.as_str())
+                .await
+                .expect("ffmpeg failed")
+                .expect("ffmpeg returned no frame");
+            let expected = expected.image.as_rgb8().unwrap();
+            let result = reprojection
+                .transform_frame(&frame, video_filter.as_str())
+                .expect("filter not supported by the reprojection");
+            assert_eq!(result.dimensions(), expected.dimensions());
+
+            let differences = result
+                .as_raw()
+                .iter()
+                .zip(expected.as_raw())
+                .map(|(a, b)| a.abs_diff(*b))
+                .collect::<Vec<_>>();
+            let mean =
+                differences.iter().map(|d| *d as f64).sum::<f64>() / differences.len() as f64;
+            let outliers = differences.iter().filter(|d| **d > 32).count();
+            assert!(
+                mean < 2.0,
+                "{video_filter}: mean pixel difference {mean:.2}"
+            );
+            assert!(
+                outliers * 100 < differences.len(),
+                "{video_filter}: {outliers} of {} values differ by more than 32",
+                differences.len()
+            );
+        }
+    }
+}
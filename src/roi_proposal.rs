@@ -0,0 +1,271 @@
+use crate::args::Args;
+use crate::ffmpeg;
+use crate::ffmpeg::FFmpegFrame;
+use log::info;
+use log::warn;
+use opencv::prelude::MatTraitConstManual;
+
+/// Width of the downscaled frames for the motion analysis
+const ANALYSIS_WIDTH: i32 = 160;
+
+/// Grid cell size in pixel of the downscaled frames
+const CELL_SIZE: i32 = 8;
+
+/// Side length in cells of a proposed ROI
+const ROI_CELLS: usize = 3;
+
+/// Shortest and longest stroke period in seconds for the periodicity score
+const MIN_PERIOD: f32 = 0.25;
+const MAX_PERIOD: f32 = 2.0;
+
+/// Mean optical flow of each grid cell over the analysed frames
+struct CellFlow {
+    columns: usize,
+    rows: usize,
+    /// [frame][cell] horizontal and vertical flow
+    flow: Vec<Vec<(f64, f64)>>,
+}
+
+/// Propose up to `count` ROIs with the strongest periodic vertical motion in the first
+/// `--propose-seconds` of the segment, the ROIs are sorted from top to bottom.
+///
+/// The analysis runs its own ffmpeg reader with the filter and `--step` of the tracking, so
+/// it sees the same (VR projected) frames in the same steps and the lags of the periodicity
+/// score are counted in steps. Strokes shorter than two steps can not be resolved. The
+/// tracking reader can only start after the ROIs are known, so the analysed range is decoded
+/// twice, this costs about `--propose-seconds` of decoding time.
+pub async fn propose_rois(args: &Args, video_fps: f32, count: usize) -> Vec<opencv::core::Rect> {
+    let mut reader_args = args.clone();
+    reader_args.end_time = Some(args.start_time + args.propose_seconds * 1000.0);
+    let mut frame_rx = ffmpeg::spawn_stream_reader(reader_args);
+
+    let mut frame_size = None;
+    let mut analysis_size = opencv::core::Size::default();
+    let mut previous: Option<opencv::core::Mat> = None;
+    let mut cells: Option<CellFlow> = None;
+    while let Some(mut frame) = frame_rx.recv().await {
+        let (width, height) = (frame.image.width() as i32, frame.image.height() as i32);
+        if frame_size.is_none() {
+            frame_size = Some((width, height));
+            analysis_size = opencv::core::Size::new(
+                ANALYSIS_WIDTH,
+                (height * ANALYSIS_WIDTH / width.max(1)).max(CELL_SIZE),
+            );
+        }
+
+        let Some(gray) = gray_frame(&mut frame, analysis_size) else {
+            continue;
+        };
+        if let Some(previous) = previous.as_ref() {
+            if let Some(flow) = cell_flow(previous, &gray) {
+                let cells = cells.get_or_insert_with(|| CellFlow {
+                    columns: (analysis_size.width / CELL_SIZE) as usize,
+                    rows: (analysis_size.height / CELL_SIZE) as usize,
+                    flow: vec![],
+                });
+                cells.flow.push(flow);
+            }
+        }
+        previous = Some(gray);
+    }
+
+    let (Some((width, height)), Some(cells)) = (frame_size, cells) else {
+        warn!("not enough frames for the ROI proposal");
+        return vec![];
+    };
+
+    let min_lag = ((MIN_PERIOD * video_fps / args.frame_step_size as f32).round() as usize).max(1);
+    let max_lag = (MAX_PERIOD * video_fps / args.frame_step_size as f32).round() as usize;
+    let scores = (0..cells.columns * cells.rows)
+        .map(|cell| {
+            let vx = cells.flow.iter().map(|f| f[cell].0).collect::<Vec<_>>();
+            let vy = cells.flow.iter().map(|f| f[cell].1).collect::<Vec<_>>();
+            periodic_motion_score(&vx, &vy, min_lag, max_lag)
+        })
+        .collect::<Vec<_>>();
+
+    // sum of the cell scores of each ROI candidate, indexed by the top left cell
+    let mut candidates = vec![];
+    for row in 0..=cells.rows.saturating_sub(ROI_CELLS) {
+        for column in 0..=cells.columns.saturating_sub(ROI_CELLS) {
+            let score = (row..(row + ROI_CELLS).min(cells.rows))
+                .flat_map(|r| {
+                    (column..(column + ROI_CELLS).min(cells.columns))
+                        .map(move |c| r * cells.columns + c)
+                })
+                .map(|cell| scores[cell])
+                .sum::<f64>();
+            candidates.push((column, row, score));
+        }
+    }
+
+    // best candidates without overlap
+    let mut selected: Vec<(usize, usize, f64)> = vec![];
+    candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+    for candidate in candidates {
+        if selected.len() >= count || candidate.2 <= 0.0 {
+            break;
+        }
+        let overlap = selected.iter().any(|s| {
+            s.0.abs_diff(candidate.0) < ROI_CELLS && s.1.abs_diff(candidate.1) < ROI_CELLS
+        });
+        if !overlap {
+            selected.push(candidate);
+        }
+    }
+
+    let scale_x = width as f64 / analysis_size.width as f64;
+    let scale_y = height as f64 / analysis_size.height as f64;
+    let mut rois = selected
+        .iter()
+        .map(|(column, row, _)| {
+            let cells = ROI_CELLS as f64 * CELL_SIZE as f64;
+            opencv::core::Rect::new(
+                (*column as f64 * CELL_SIZE as f64 * scale_x).round() as i32,
+                (*row as f64 * CELL_SIZE as f64 * scale_y).round() as i32,
+                (cells * scale_x).round() as i32,
+                (cells * scale_y).round() as i32,
+            )
+        })
+        .collect::<Vec<_>>();
+    rois.sort_by_key(|roi| roi.y);
+
+    info!(
+        "proposed ROIs from {} frames: {:?}",
+        cells.flow.len() + 1,
+        rois
+    );
+    rois
+}
+
+/// Downscaled gray version of `frame`
+fn gray_frame(frame: &mut FFmpegFrame, size: opencv::core::Size) -> Option<opencv::core::Mat> {
+    let gray = frame.get_gray_frame()?;
+    let mut result = opencv::core::Mat::default();
+    opencv::imgproc::resize(
+        &gray,
+        &mut result,
+        size,
+        0.0,
+        0.0,
+        opencv::imgproc::INTER_AREA,
+    )
+    .ok()?;
+    Some(result)
+}
+
+/// Mean Farneback optical flow of each grid cell
+fn cell_flow(previous: &opencv::core::Mat, next: &opencv::core::Mat) -> Option<Vec<(f64, f64)>> {
+    let mut flow = opencv::core::Mat::default();
+    opencv::video::calc_optical_flow_farneback(previous, next, &mut flow, 0.5, 3, 15, 3, 5, 1.2, 0)
+        .ok()?;
+
+    let size = flow.size().ok()?;
+    let data = flow.data_typed::<opencv::core::Vec2f>().ok()?;
+    let columns = (size.width / CELL_SIZE) as usize;
+    let rows = (size.height / CELL_SIZE) as usize;
+    let mut cells = vec![(0.0, 0.0); columns * rows];
+    for (row, cell_row) in cells.chunks_mut(columns.max(1)).enumerate() {
+        for (column, cell) in cell_row.iter_mut().enumerate() {
+            let mut sum = (0.0, 0.0);
+            for y in row * CELL_SIZE as usize..(row + 1) * CELL_SIZE as usize {
+                for x in column * CELL_SIZE as usize..(column + 1) * CELL_SIZE as usize {
+                    let value = data[y * size.width as usize + x];
+                    sum.0 += value[0] as f64;
+                    sum.1 += value[1] as f64;
+                }
+            }
+            let pixels = (CELL_SIZE * CELL_SIZE) as f64;
+            *cell = (sum.0 / pixels, sum.1 / pixels);
+        }
+    }
+    Some(cells)
+}
+
+/// Standard deviation of the vertical flow weighted with its strongest autocorrelation
+/// for lags between `min_lag` and `max_lag` frames and its share of the total motion
+fn periodic_motion_score(vx: &[f64], vy: &[f64], min_lag: usize, max_lag: usize) -> f64 {
+    let n = vy.len();
+    let max_lag = max_lag.min(n / 2);
+    if n < 4 || min_lag > max_lag {
+        return 0.0;
+    }
+
+    let mean = vy.iter().sum::<f64>() / n as f64;
+    let centered = vy.iter().map(|v| v - mean).collect::<Vec<_>>();
+    let variance = centered.iter().map(|v| v * v).sum::<f64>() / n as f64;
+    if variance <= f64::EPSILON {
+        return 0.0;
+    }
+
+    let periodicity = (min_lag..=max_lag)
+        .map(|lag| {
+            centered
+                .iter()
+                .zip(centered.iter().skip(lag))
+                .map(|(a, b)| a * b)
+                .sum::<f64>()
+                / ((n - lag) as f64 * variance)
+        })
+        .fold(0.0, f64::max);
+    let horizontal = vx.iter().map(|v| v * v).sum::<f64>() / n as f64;
+
+    variance.sqrt() * periodicity * variance / (variance + horizontal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(n: usize, period: f64, amplitude: f64) -> Vec<f64> {
+        (0..n)
+            .map(|i| amplitude * (2.0 * std::f64::consts::PI * i as f64 / period).sin())
+            .collect()
+    }
+
+    /// Uniform noise in [-amplitude, amplitude) from a linear congruential generator
+    fn noise(n: usize, amplitude: f64) -> Vec<f64> {
+        let mut state: u64 = 7;
+        (0..n)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                amplitude * ((state >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn periodic_motion_score_sine_and_noise() {
+        let n = 120;
+        let still = vec![0.0; n];
+
+        // period of 20 frames within the lags, the score is close to the standard deviation
+        let periodic = periodic_motion_score(&still, &sine(n, 20.0, 2.0), 5, 40);
+        assert!((periodic - 2.0 / 2f64.sqrt()).abs() < 0.1, "{periodic}");
+
+        // noise with about the same standard deviation has no strong autocorrelation
+        let random = periodic_motion_score(&still, &noise(n, 2.0 * 1.5f64.sqrt()), 5, 40);
+        assert!(random < 0.3 * periodic, "{random} {periodic}");
+
+        // noise on the sine lowers the score but keeps it above the noise alone
+        let noisy = sine(n, 20.0, 2.0)
+            .iter()
+            .zip(noise(n, 1.0))
+            .map(|(a, b)| a + b)
+            .collect::<Vec<_>>();
+        let noisy = periodic_motion_score(&still, &noisy, 5, 40);
+        assert!(noisy > 2.0 * random && noisy < 1.1 * periodic, "{noisy}");
+
+        // the same motion in both directions halves the score
+        let diagonal = periodic_motion_score(&sine(n, 20.0, 2.0), &sine(n, 20.0, 2.0), 5, 40);
+        assert!((diagonal - periodic / 2.0).abs() < 1e-9, "{diagonal}");
+
+        assert_eq!(periodic_motion_score(&still, &still, 5, 40), 0.0);
+        assert_eq!(
+            periodic_motion_score(&still[..3], &sine(3, 20.0, 2.0), 1, 2),
+            0.0
+        );
+    }
+}
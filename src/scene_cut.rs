@@ -0,0 +1,68 @@
+use crate::ffmpeg::FrameBuffer;
+
+/// Bins per color channel of the frame histograms
+const HISTOGRAM_BINS: usize = 16;
+
+/// Only every n-th pixel in both directions is counted
+const PIXEL_STRIDE: usize = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SceneCutAction {
+    None,
+    /// Stop tracking at the first scene cut
+    Stop,
+    /// Select new ROIs on the first frame after the cut
+    Reselect,
+}
+
+/// Detect scene cuts by the color histogram difference of consecutive frames
+pub struct SceneCutDetector {
+    threshold: f64,
+    previous: Option<Vec<f64>>,
+}
+
+impl SceneCutDetector {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            previous: None,
+        }
+    }
+
+    /// Returns true when `frame` is the first frame of a new scene
+    pub fn is_cut(&mut self, frame: &FrameBuffer) -> bool {
+        let histogram = histogram(frame);
+        let cut = match self.previous.as_ref() {
+            Some(previous) => distance(previous, &histogram) > self.threshold,
+            None => false,
+        };
+        self.previous = Some(histogram);
+        cut
+    }
+}
+
+/// Normalized histogram of each color channel
+fn histogram(frame: &FrameBuffer) -> Vec<f64> {
+    let mut histogram = vec![0.0; 3 * HISTOGRAM_BINS];
+    let mut count = 0;
+    for y in (0..frame.height()).step_by(PIXEL_STRIDE) {
+        for x in (0..frame.width()).step_by(PIXEL_STRIDE) {
+            let pixel = frame.get_pixel(x, y);
+            for (channel, value) in pixel.0.iter().enumerate() {
+                let bin = *value as usize * HISTOGRAM_BINS / 256;
+                histogram[channel * HISTOGRAM_BINS + bin] += 1.0;
+            }
+            count += 1;
+        }
+    }
+    histogram
+        .iter_mut()
+        .for_each(|value| *value /= count.max(1) as f64);
+    histogram
+}
+
+/// Half of the L1 distance averaged over the channels, 0 for equal and 1 for disjoint
+/// histograms
+fn distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| (a - b).abs()).sum::<f64>() / 6.0
+}
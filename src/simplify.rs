@@ -1,14 +1,322 @@
-pub fn rdp(score: Vec<mint::Point2<i32>>, epsilon: f64) -> Vec<mint::Point2<i32>> {
-    let mut keep = (0..score.len()).collect();
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
 
-    if epsilon > 0.01 {
-        keep = ramer_douglas_peucker::rdp(&score, epsilon);
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SimplifyMethod {
+    /// Ramer–Douglas–Peucker with `--epsilon`
+    Rdp,
+    /// Turning points with a minimum height of `--prominence`
+    Peaks,
+    /// Visvalingam–Whittaker with a minimum triangle area of `--min-area`
+    Visvalingam,
+}
+
+pub fn simplify(
+    score: Vec<mint::Point2<f64>>,
+    method: SimplifyMethod,
+    epsilon: f64,
+    prominence: f64,
+    min_area: f64,
+) -> Vec<mint::Point2<f64>> {
+    match method {
+        SimplifyMethod::Rdp => rdp(score, epsilon),
+        SimplifyMethod::Peaks => peaks(score, prominence),
+        SimplifyMethod::Visvalingam => visvalingam(score, min_area),
+    }
+}
+
+pub fn rdp(score: Vec<mint::Point2<f64>>, epsilon: f64) -> Vec<mint::Point2<f64>> {
+    if epsilon <= 0.01 {
+        return score;
+    }
+
+    let mut keep = vec![false; score.len()];
+    for idx in ramer_douglas_peucker::rdp(&score, epsilon) {
+        keep[idx] = true;
     }
 
     score
         .into_iter()
-        .enumerate()
-        .filter(|(idx, _)| keep.contains(idx))
-        .map(|(_, val)| val)
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|(val, _)| val)
         .collect::<Vec<_>>()
 }
+
+/// Keep the turning points of the score, a stroke smaller than `prominence` is merged into
+/// the surrounding stroke. The first and last point are always kept. Before the first stroke
+/// the lowest (highest) point is kept as its start, even when it is closer than `prominence`
+/// to the first point.
+pub fn peaks(score: Vec<mint::Point2<f64>>, prominence: f64) -> Vec<mint::Point2<f64>> {
+    if score.len() < 3 {
+        return score;
+    }
+
+    let mut keep = vec![0];
+    // +1.0 for a rising and -1.0 for a falling stroke, 0.0 until the first stroke is found
+    let mut direction = 0.0;
+    // lowest and highest point before the first stroke
+    let (mut low, mut high) = (0, 0);
+    for (idx, point) in score.iter().enumerate().skip(1) {
+        let diff = point.y - score[*keep.last().unwrap()].y;
+        if direction == 0.0 {
+            let start = if point.y - score[low].y >= prominence && point.y > score[low].y {
+                direction = 1.0;
+                low
+            } else if score[high].y - point.y >= prominence && point.y < score[high].y {
+                direction = -1.0;
+                high
+            } else {
+                if point.y < score[low].y {
+                    low = idx;
+                }
+                if point.y > score[high].y {
+                    high = idx;
+                }
+                continue;
+            };
+            if start != 0 {
+                keep.push(start);
+            }
+            keep.push(idx);
+        } else if diff * direction > 0.0 {
+            // the current stroke continues, move its turning point
+            *keep.last_mut().unwrap() = idx;
+        } else if -diff * direction >= prominence && diff != 0.0 {
+            direction = -direction;
+            keep.push(idx);
+        }
+    }
+
+    if *keep.last().unwrap() != score.len() - 1 {
+        keep.push(score.len() - 1);
+    }
+
+    keep.into_iter().map(|idx| score[idx]).collect()
+}
+
+/// Remove points with an effective triangle area (frames × position) below `min_area`
+pub fn visvalingam(score: Vec<mint::Point2<f64>>, min_area: f64) -> Vec<mint::Point2<f64>> {
+    visvalingam_reduce(score, |_, area| area >= min_area)
+}
+
+/// Remove the least significant points until at most `max_actions` points are left
+pub fn limit_actions(score: Vec<mint::Point2<f64>>, max_actions: usize) -> Vec<mint::Point2<f64>> {
+    visvalingam_reduce(score, |remaining, _| remaining <= max_actions.max(2))
+}
+
+/// Remove the least significant points until no window of one second contains more than
+/// `actions_per_second` points. A fractional rate stretches the window to a whole number of
+/// actions, e.g. 2.5 actions per second allow 3 actions within 1.2 seconds.
+pub fn limit_rate(
+    mut score: Vec<mint::Point2<f64>>,
+    video_fps: f32,
+    actions_per_second: f64,
+) -> Vec<mint::Point2<f64>> {
+    let per_window = actions_per_second.ceil().max(1.0) as usize;
+    let window_in_frames = per_window as f64 / actions_per_second * video_fps as f64;
+
+    let mut idx = 0;
+    while idx + per_window < score.len() {
+        if score[idx + per_window].x - score[idx].x >= window_in_frames {
+            idx += 1;
+            continue;
+        }
+
+        // the first and last point are always kept
+        let area = |i: usize| triangle_area(&score[i - 1], &score[i], &score[i + 1]);
+        let least_significant = (idx..=idx + per_window)
+            .filter(|i| *i > 0 && *i < score.len() - 1)
+            .min_by(|a, b| area(*a).total_cmp(&area(*b)));
+        match least_significant {
+            Some(i) => {
+                score.remove(i);
+                // the removal changed the windows that end after the removed point
+                idx = idx.saturating_sub(per_window);
+            }
+            None => idx += 1,
+        }
+    }
+
+    score
+}
+
+fn triangle_area(a: &mint::Point2<f64>, b: &mint::Point2<f64>, c: &mint::Point2<f64>) -> f64 {
+    ((b.x - a.x) * (c.y - a.y) - (c.x - a.x) * (b.y - a.y)).abs() / 2.0
+}
+
+struct Candidate {
+    area: f64,
+    idx: usize,
+    version: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    // NOTE: reversed to get a min heap from BinaryHeap
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .area
+            .total_cmp(&self.area)
+            .then_with(|| other.idx.cmp(&self.idx))
+    }
+}
+
+/// Visvalingam–Whittaker: repeatedly remove the point with the smallest effective area
+/// until `done(remaining_points, smallest_area)` returns true
+fn visvalingam_reduce(
+    score: Vec<mint::Point2<f64>>,
+    mut done: impl FnMut(usize, f64) -> bool,
+) -> Vec<mint::Point2<f64>> {
+    let n = score.len();
+    if n < 3 {
+        return score;
+    }
+
+    let mut prev = (0..n).map(|i| i.saturating_sub(1)).collect::<Vec<_>>();
+    let mut next = (0..n).map(|i| (i + 1).min(n - 1)).collect::<Vec<_>>();
+    let mut version = vec![0; n];
+    let mut removed = vec![false; n];
+    let mut heap = (1..n - 1)
+        .map(|idx| Candidate {
+            area: triangle_area(&score[idx - 1], &score[idx], &score[idx + 1]),
+            idx,
+            version: 0,
+        })
+        .collect::<BinaryHeap<_>>();
+
+    let mut remaining = n;
+    while let Some(candidate) = heap.pop() {
+        if removed[candidate.idx] || candidate.version != version[candidate.idx] {
+            continue;
+        }
+
+        if done(remaining, candidate.area) {
+            break;
+        }
+
+        removed[candidate.idx] = true;
+        remaining -= 1;
+        let (p, q) = (prev[candidate.idx], next[candidate.idx]);
+        next[p] = q;
+        prev[q] = p;
+
+        for idx in [p, q] {
+            if idx == 0 || idx == n - 1 {
+                continue;
+            }
+            version[idx] += 1;
+            heap.push(Candidate {
+                // the effective area of a neighbour never drops below the removed area
+                area: triangle_area(&score[prev[idx]], &score[idx], &score[next[idx]])
+                    .max(candidate.area),
+                idx,
+                version: version[idx],
+            });
+        }
+    }
+
+    score
+        .into_iter()
+        .zip(removed)
+        .filter(|(_, removed)| !*removed)
+        .map(|(val, _)| val)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(y: &[f64]) -> Vec<mint::Point2<f64>> {
+        y.iter()
+            .enumerate()
+            .map(|(x, y)| mint::Point2 { x: x as f64, y: *y })
+            .collect()
+    }
+
+    fn ys(score: &[mint::Point2<f64>]) -> Vec<f64> {
+        score.iter().map(|p| p.y).collect()
+    }
+
+    #[test]
+    fn peaks_keeps_turning_points() {
+        assert!(peaks(vec![], 10.0).is_empty());
+        assert_eq!(ys(&peaks(points(&[0.0, 100.0]), 10.0)), [0.0, 100.0]);
+
+        // the dip of 5 is smaller than the prominence and merged into the rising stroke
+        let score = points(&[0.0, 50.0, 100.0, 95.0, 100.0, 60.0, 0.0, 20.0, 100.0, 90.0]);
+        assert_eq!(ys(&peaks(score, 10.0)), [0.0, 100.0, 0.0, 100.0, 90.0]);
+    }
+
+    #[test]
+    fn peaks_keeps_initial_turning_point() {
+        // the minimum before the first stroke is closer than the prominence to the start
+        let score = points(&[50.0, 44.0, 100.0, 0.0]);
+        assert_eq!(ys(&peaks(score, 10.0)), [50.0, 44.0, 100.0, 0.0]);
+
+        let score = points(&[50.0, 56.0, 52.0, 0.0, 100.0]);
+        assert_eq!(ys(&peaks(score, 10.0)), [50.0, 56.0, 0.0, 100.0]);
+
+        // no stroke at all
+        let score = points(&[50.0, 52.0, 48.0, 51.0]);
+        assert_eq!(ys(&peaks(score, 10.0)), [50.0, 51.0]);
+    }
+
+    #[test]
+    fn visvalingam_removes_small_areas() {
+        assert_eq!(visvalingam(points(&[0.0, 100.0]), 10.0).len(), 2);
+
+        // collinear points have no area, the small bump has an area of 4
+        let score = points(&[0.0, 25.0, 50.0, 75.0, 100.0, 104.0, 100.0]);
+        assert_eq!(
+            ys(&visvalingam(score.clone(), 1.0)),
+            [0.0, 100.0, 104.0, 100.0]
+        );
+        assert_eq!(ys(&visvalingam(score, 5.0)), [0.0, 100.0, 100.0]);
+    }
+
+    #[test]
+    fn limit_actions_keeps_the_largest_strokes() {
+        let score = points(&[0.0, 100.0, 95.0, 100.0, 0.0, 5.0, 0.0]);
+        let limited = limit_actions(score.clone(), 3);
+        assert_eq!(ys(&limited), [0.0, 100.0, 0.0]);
+        assert_eq!(limit_actions(score, 0).len(), 2);
+    }
+
+    #[test]
+    fn limit_rate_caps_every_window() {
+        let y = (0..120)
+            .map(|i| if i % 2 == 0 { 0.0 } else { 100.0 })
+            .collect::<Vec<_>>();
+        for (fps, rate) in [(30.0, 4.0), (30.0, 2.5), (60.0, 1.0)] {
+            let limited = limit_rate(points(&y), fps, rate);
+            assert_eq!(limited.first().unwrap().x, 0.0);
+            assert_eq!(limited.last().unwrap().x, 119.0);
+
+            // at most ceil(rate) + 1 points within ceil(rate) / rate seconds
+            let per_window = rate.ceil() as usize;
+            let window = per_window as f64 / rate * fps as f64;
+            for pair in limited.windows(per_window + 1) {
+                assert!(pair[per_window].x - pair[0].x >= window, "{fps} {rate}");
+            }
+        }
+
+        // a score below the rate is not changed
+        let score = points(&[0.0, 100.0, 0.0]);
+        assert_eq!(limit_rate(score.clone(), 1.0, 10.0).len(), score.len());
+    }
+}
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+
+/// Number of actions changed by `limit_speed`
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpeedLimitReport {
+    /// Actions dropped because they were closer than the minimum interval
+    pub merged: usize,
+    /// Actions with reduced amplitude because of the maximum speed
+    pub clamped: usize,
+}
+
+/// Make the score executable by a device: actions closer than `min_interval_in_ms` are
+/// merged into the more significant one and amplitudes are clamped so no move is faster
+/// than `max_speed` (position units per second)
+pub fn limit_speed(
+    score: Vec<mint::Point2<f64>>,
+    video_fps: f32,
+    max_speed: Option<f64>,
+    min_interval_in_ms: Option<f64>,
+) -> (Vec<mint::Point2<f64>>, SpeedLimitReport) {
+    let frame_time_in_ms = 1000.0 / video_fps as f64;
+    let mut report = SpeedLimitReport::default();
+
+    let mut result: Vec<mint::Point2<f64>> = Vec::with_capacity(score.len());
+    for point in score {
+        let n = result.len();
+        if let Some(min_interval_in_ms) = min_interval_in_ms {
+            if n > 0 && (point.x - result[n - 1].x) * frame_time_in_ms < min_interval_in_ms {
+                report.merged += 1;
+                // keep the action with the larger distance to the action before
+                if n > 1
+                    && (point.y - result[n - 2].y).abs() > (result[n - 1].y - result[n - 2].y).abs()
+                {
+                    result[n - 1] = point;
+                }
+                continue;
+            }
+        }
+        result.push(point);
+    }
+
+    if let Some(max_speed) = max_speed {
+        for idx in 1..result.len() {
+            let duration_in_seconds =
+                (result[idx].x - result[idx - 1].x) * frame_time_in_ms / 1000.0;
+            let max_distance = max_speed * duration_in_seconds;
+            let distance = result[idx].y - result[idx - 1].y;
+            if distance.abs() > max_distance + f64::EPSILON {
+                result[idx].y = result[idx - 1].y + distance.signum() * max_distance;
+                report.clamped += 1;
+            }
+        }
+    }
+
+    (result, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(actions: &[(f64, f64)]) -> Vec<mint::Point2<f64>> {
+        actions
+            .iter()
+            .map(|(x, y)| mint::Point2 { x: *x, y: *y })
+            .collect()
+    }
+
+    fn actions(score: &[mint::Point2<f64>]) -> Vec<(f64, f64)> {
+        score.iter().map(|p| (p.x, p.y)).collect()
+    }
+
+    #[test]
+    fn edge_cases() {
+        let (score, report) = limit_speed(vec![], 30.0, Some(100.0), Some(100.0));
+        assert!(score.is_empty());
+        assert_eq!(report, SpeedLimitReport::default());
+
+        let (score, report) = limit_speed(points(&[(3.0, 80.0)]), 30.0, Some(1.0), Some(1e6));
+        assert_eq!(actions(&score), [(3.0, 80.0)]);
+        assert_eq!(report, SpeedLimitReport::default());
+
+        let input = points(&[(0.0, 0.0), (1.0, 100.0), (2.0, 0.0)]);
+        let (score, report) = limit_speed(input.clone(), 30.0, None, None);
+        assert_eq!(actions(&score), actions(&input));
+        assert_eq!(report, SpeedLimitReport::default());
+    }
+
+    #[test]
+    fn max_speed_clamps_the_amplitude() {
+        // 30 frames at 30 fps are one second
+        let input = points(&[(0.0, 0.0), (30.0, 100.0), (45.0, 100.0), (60.0, 70.0)]);
+        let (score, report) = limit_speed(input, 30.0, Some(50.0), None);
+        let expected = [(0.0, 0.0), (30.0, 50.0), (45.0, 75.0), (60.0, 70.0)];
+        for ((x, y), (expected_x, expected_y)) in actions(&score).into_iter().zip(expected) {
+            assert_eq!(x, expected_x);
+            assert!((y - expected_y).abs() < 1e-9, "{y} {expected_y}");
+        }
+        assert_eq!(report.clamped, 2);
+        assert_eq!(report.merged, 0);
+    }
+
+    #[test]
+    fn min_interval_merges_close_actions() {
+        // one frame at 30 fps is 33 ms
+        let input = points(&[
+            (0.0, 0.0),
+            (1.0, 10.0),
+            (2.0, 90.0),
+            (10.0, 50.0),
+            (11.0, 0.0),
+        ]);
+        let (score, report) = limit_speed(input, 30.0, None, Some(50.0));
+        // the first close action is dropped, the second replaces the smaller move
+        assert_eq!(actions(&score), [(0.0, 0.0), (2.0, 90.0), (11.0, 0.0)]);
+        assert_eq!(report.merged, 2);
+        assert_eq!(report.clamped, 0);
+
+        let input = points(&[(0.0, 0.0), (10.0, 100.0), (11.0, 60.0)]);
+        let (score, report) = limit_speed(input, 30.0, None, Some(50.0));
+        assert_eq!(actions(&score), [(0.0, 0.0), (10.0, 100.0)]);
+        assert_eq!(report.merged, 1);
+    }
+}
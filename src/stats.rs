@@ -0,0 +1,72 @@
+use log::info;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STAGES: Lazy<Mutex<BTreeMap<String, Stage>>> = Lazy::new(Default::default);
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Stage {
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+/// Start collecting stage timings, without it `record` does nothing
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Add the time since `start` to `stage`
+pub fn record(stage: &str, start: Instant) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let duration = start.elapsed();
+    let mut stages = STAGES.lock().unwrap();
+    let entry = match stages.get_mut(stage) {
+        Some(entry) => entry,
+        None => stages.entry(stage.to_string()).or_default(),
+    };
+    entry.count += 1;
+    entry.total += duration;
+    entry.max = entry.max.max(duration);
+}
+
+/// Log the collected timings, the overall tracking speed and the number of frames dropped by
+/// the forward-backward check, `None` without the check
+pub fn report(frames: u32, dropped_frames: Option<usize>, tracking_duration: Duration) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    info!(
+        "{:<36} {:>8} {:>12} {:>10} {:>10}",
+        "stage", "count", "total [ms]", "mean [ms]", "max [ms]"
+    );
+    for (stage, timing) in STAGES.lock().unwrap().iter() {
+        let total = timing.total.as_secs_f64() * 1000.0;
+        info!(
+            "{:<36} {:>8} {:>12.1} {:>10.3} {:>10.3}",
+            stage,
+            timing.count,
+            total,
+            total / timing.count.max(1) as f64,
+            timing.max.as_secs_f64() * 1000.0
+        );
+    }
+    info!(
+        "tracked {frames} frames in {:.1} s ({:.1} fps)",
+        tracking_duration.as_secs_f64(),
+        frames as f64 / tracking_duration.as_secs_f64().max(0.001)
+    );
+    if let Some(dropped_frames) = dropped_frames {
+        info!("forward-backward check failed for {dropped_frames} frames");
+    }
+}
@@ -1,57 +1,496 @@
 use crate::ffmpeg::FFmpegFrame;
+use crate::stats;
 use log::error;
+use log::warn;
+use opencv::video::DenseOpticalFlow;
 use opencv::video::Tracker;
+use std::collections::VecDeque;
+
+const CONTROL_CHANNEL_CAPACITY: usize = 8;
 
 pub struct OpencvTracker {
     obj: opencv::core::Ptr<dyn opencv::tracking::TrackerCSRT>,
 }
 
+impl OpencvTracker {
+    pub fn csrt() -> Self {
+        let tracker_param: opencv::tracking::TrackerCSRT_Params =
+            opencv::tracking::TrackerCSRT_Params::default().unwrap();
+        Self {
+            obj: <dyn opencv::tracking::TrackerCSRT>::create(&tracker_param).unwrap(),
+        }
+    }
+
+    pub fn init(&mut self, frame: &mut FFmpegFrame, init_box: opencv::core::Rect) -> bool {
+        let mut success = true;
+        let mut opencv_frame = frame.get_opencv_frame();
+        opencv_frame.with_mut(|frame| {
+            if self.obj.init(frame.mat, init_box).is_err() {
+                success = false;
+            }
+        });
+        success
+    }
+
+    pub fn update(
+        &mut self,
+        frame: &mut FFmpegFrame,
+        bounding_box: &mut opencv::core::Rect,
+    ) -> bool {
+        let mut success = true;
+        let mut opencv_frame = frame.get_opencv_frame();
+        opencv_frame.with_mut(|frame| {
+            if self.obj.update(frame.mat, bounding_box).is_err() {
+                success = false;
+            }
+        });
+        success
+    }
+}
+
+/// Move the box with the mean dense optical flow inside of it. The flow is computed on the
+/// box with a margin of half the box size for context, the sub pixel box position integrates
+/// the flow so small motions per frame are not lost by rounding.
+pub struct OpticalFlowTracker {
+    obj: opencv::core::Ptr<dyn opencv::video::DISOpticalFlow>,
+    previous: opencv::core::Mat,
+    /// top left corner of the box
+    position: (f64, f64),
+    size: opencv::core::Size,
+}
+
+impl OpticalFlowTracker {
+    pub fn dis() -> Self {
+        Self {
+            obj: <dyn opencv::video::DISOpticalFlow>::create(
+                opencv::video::DISOpticalFlow_PRESET_MEDIUM,
+            )
+            .unwrap(),
+            previous: opencv::core::Mat::default(),
+            position: (0.0, 0.0),
+            size: opencv::core::Size::default(),
+        }
+    }
+
+    pub fn init(&mut self, frame: &mut FFmpegFrame, init_box: opencv::core::Rect) -> bool {
+        let Some(gray) = frame.get_gray_frame() else {
+            return false;
+        };
+        self.previous = gray;
+        self.position = (init_box.x as f64, init_box.y as f64);
+        self.size = init_box.size();
+        true
+    }
+
+    pub fn update(
+        &mut self,
+        frame: &mut FFmpegFrame,
+        bounding_box: &mut opencv::core::Rect,
+    ) -> bool {
+        let Some(gray) = frame.get_gray_frame() else {
+            return false;
+        };
+
+        let (frame_width, frame_height) = (frame.image.width() as i32, frame.image.height() as i32);
+        let x = self.position.0.round() as i32;
+        let y = self.position.1.round() as i32;
+        let (margin_x, margin_y) = (self.size.width / 2, self.size.height / 2);
+        let left = (x - margin_x).clamp(0, frame_width);
+        let top = (y - margin_y).clamp(0, frame_height);
+        let right = (x + self.size.width + margin_x).clamp(0, frame_width);
+        let bottom = (y + self.size.height + margin_y).clamp(0, frame_height);
+        let area = opencv::core::Rect::new(left, top, right - left, bottom - top);
+        // box relative to the flow area
+        let inner_left = x.clamp(left, right);
+        let inner_top = y.clamp(top, bottom);
+        let inner = opencv::core::Rect::new(
+            inner_left - left,
+            inner_top - top,
+            (x + self.size.width).clamp(left, right) - inner_left,
+            (y + self.size.height).clamp(top, bottom) - inner_top,
+        );
+        if inner.width < 1 || inner.height < 1 {
+            // the box left the frame
+            return false;
+        }
+
+        let (Ok(previous), Ok(next)) = (
+            opencv::core::Mat::roi(&self.previous, area),
+            opencv::core::Mat::roi(&gray, area),
+        ) else {
+            return false;
+        };
+        let mut flow = opencv::core::Mat::default();
+        if self.obj.calc(&previous, &next, &mut flow).is_err() {
+            return false;
+        }
+        let Ok(box_flow) = opencv::core::Mat::roi(&flow, inner) else {
+            return false;
+        };
+        let Ok(mean) = opencv::core::mean(&box_flow, &opencv::core::no_array()) else {
+            return false;
+        };
+
+        self.position.0 += mean[0];
+        self.position.1 += mean[1];
+        self.previous = gray;
+        *bounding_box = opencv::core::Rect::new(
+            self.position.0.round() as i32,
+            self.position.1.round() as i32,
+            self.size.width,
+            self.size.height,
+        );
+        true
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TrackerKind {
+    /// OpenCV CSRT box tracker
+    Csrt,
+    /// Integrate the mean dense optical flow (DIS) inside the box, for features that change
+    /// their appearance while moving
+    OpticalFlow,
+}
+
+/// Box tracker selected with `--tracker`
+pub enum FeatureTracker {
+    Csrt(OpencvTracker),
+    OpticalFlow(OpticalFlowTracker),
+}
+
+impl FeatureTracker {
+    pub fn new(kind: TrackerKind) -> Self {
+        match kind {
+            TrackerKind::Csrt => Self::Csrt(OpencvTracker::csrt()),
+            TrackerKind::OpticalFlow => Self::OpticalFlow(OpticalFlowTracker::dis()),
+        }
+    }
+
+    pub fn init(&mut self, frame: &mut FFmpegFrame, init_box: opencv::core::Rect) -> bool {
+        match self {
+            Self::Csrt(tracker) => tracker.init(frame, init_box),
+            Self::OpticalFlow(tracker) => tracker.init(frame, init_box),
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        frame: &mut FFmpegFrame,
+        bounding_box: &mut opencv::core::Rect,
+    ) -> bool {
+        match self {
+            Self::Csrt(tracker) => tracker.update(frame, bounding_box),
+            Self::OpticalFlow(tracker) => tracker.update(frame, bounding_box),
+        }
+    }
+}
+
+/// Tracker backend and the optional forward-backward check of a tracker task
+#[derive(Copy, Clone, Debug)]
+pub struct TrackerSettings {
+    pub kind: TrackerKind,
+    pub fb_check: Option<ForwardBackwardCheck>,
+}
+
+/// Settings of the forward-backward consistency check
+#[derive(Copy, Clone, Debug)]
+pub struct ForwardBackwardCheck {
+    /// Number of frames tracked backward from the last forward box
+    pub window: usize,
+    /// Maximum distance of the forward and backward box center relative to the box size
+    pub threshold: f64,
+}
+
+/// Distance of the box centers
+pub fn center_distance(a: &opencv::core::Rect, b: &opencv::core::Rect) -> f64 {
+    let dx = (a.x + a.width / 2 - b.x - b.width / 2) as f64;
+    let dy = (a.y + a.height / 2 - b.y - b.height / 2) as f64;
+    dx.hypot(dy)
+}
+
+/// Track `frames` backward starting with the last forward box and flag the frames where the
+/// backward box diverge from the forward box. Frames before a lost backward tracking are
+/// flagged too.
+pub fn backward_check(
+    kind: TrackerKind,
+    frames: &mut [FFmpegFrame],
+    boxes: &[opencv::core::Rect],
+    threshold: f64,
+) -> Vec<bool> {
+    let n = frames.len().min(boxes.len());
+    let mut flags = vec![false; boxes.len()];
+    if n < 2 {
+        return flags;
+    }
+
+    let mut tracker = FeatureTracker::new(kind);
+    if !tracker.init(&mut frames[n - 1], boxes[n - 1]) {
+        warn!("backward tracker setup failed");
+        return flags;
+    }
+
+    let mut bounding_box = boxes[n - 1];
+    for i in (0..n - 1).rev() {
+        if !tracker.update(&mut frames[i], &mut bounding_box) {
+            flags[..=i].iter_mut().for_each(|flag| *flag = true);
+            break;
+        }
+        let size = boxes[i].width.max(boxes[i].height) as f64;
+        flags[i] = center_distance(&bounding_box, &boxes[i]) > threshold * size;
+    }
+
+    flags
+}
+
+/// Request to restart a tracker with a new box on an already received frame
+pub struct TrackerReinit {
+    generation: u32,
+    frame_number: u32,
+    frame: FFmpegFrame<'static>,
+    init_box: opencv::core::Rect,
+}
+
+pub struct TrackingBox {
+    generation: u32,
+    frame_number: u32,
+    rect: opencv::core::Rect,
+    /// Failed forward-backward check
+    flagged: bool,
+}
+
+/// Main thread side of a tracker task
+pub struct TrackerHandle {
+    generation: u32,
+    control: tokio::sync::mpsc::Sender<TrackerReinit>,
+    boxes: tokio::sync::mpsc::Receiver<TrackingBox>,
+}
+
+impl TrackerHandle {
+    /// Start tracking `init_box` on the first frame of `consumer`. The tracker keep the last
+    /// `history_size` frames to replay them after a re-initialization. With a forward-backward
+    /// check in `settings` the boxes are sent once the window of the check is complete.
+    pub fn spawn(
+        feature: usize,
+        init_box: opencv::core::Rect,
+        consumer: tokio::sync::mpsc::Receiver<FFmpegFrame<'static>>,
+        channel_capacity: usize,
+        history_size: usize,
+        settings: TrackerSettings,
+    ) -> Self {
+        let (control_tx, control_rx) =
+            tokio::sync::mpsc::channel::<TrackerReinit>(CONTROL_CHANNEL_CAPACITY);
+        let (box_tx, box_rx) = tokio::sync::mpsc::channel::<TrackingBox>(channel_capacity);
+
+        tokio::task::spawn_blocking(move || {
+            tokio::runtime::Handle::current().block_on(track_feature(
+                feature,
+                init_box,
+                consumer,
+                control_rx,
+                box_tx,
+                history_size,
+                settings,
+            ));
+        });
+
+        Self {
+            generation: 0,
+            control: control_tx,
+            boxes: box_rx,
+        }
+    }
+
+    /// Tracking box for the given frame together with the forward-backward check flag,
+    /// boxes from before the last re-initialization and from older frames are dropped
+    pub async fn next_box(&mut self, frame_number: u32) -> Option<(opencv::core::Rect, bool)> {
+        while let Some(tracking_box) = self.boxes.recv().await {
+            if tracking_box.generation == self.generation
+                && tracking_box.frame_number >= frame_number
+            {
+                return Some((tracking_box.rect, tracking_box.flagged));
+            }
+        }
+        None
+    }
+
+    /// Drop all boxes that are already available without waiting for new ones
+    pub fn drop_pending(&mut self) {
+        while self.boxes.try_recv().is_ok() {}
+    }
+
+    /// Restart tracking with `init_box` on `frame`. The tracker replays all frames after
+    /// `frame_number` it already processed, use `next_box` to receive the new boxes.
+    pub async fn reinit(
+        &mut self,
+        frame_number: u32,
+        frame: FFmpegFrame<'static>,
+        init_box: opencv::core::Rect,
+    ) -> bool {
+        self.generation += 1;
+        self.control
+            .send(TrackerReinit {
+                generation: self.generation,
+                frame_number,
+                frame,
+                init_box,
+            })
+            .await
+            .is_ok()
+    }
+}
+
 pub async fn track_feature(
+    feature: usize,
     init_box: opencv::core::Rect,
     mut consumer: tokio::sync::mpsc::Receiver<FFmpegFrame<'_>>,
-    producer: tokio::sync::mpsc::Sender<opencv::core::Rect>,
+    mut control: tokio::sync::mpsc::Receiver<TrackerReinit>,
+    producer: tokio::sync::mpsc::Sender<TrackingBox>,
+    history_size: usize,
+    settings: TrackerSettings,
 ) {
-    let tracker_param: opencv::tracking::TrackerCSRT_Params =
-        opencv::tracking::TrackerCSRT_Params::default().unwrap();
-    let mut tracker: OpencvTracker = OpencvTracker {
-        obj: <dyn opencv::tracking::TrackerCSRT>::create(&tracker_param).unwrap(),
-    };
+    let mut tracker = FeatureTracker::new(settings.kind);
 
     let Some(mut init_frame) = consumer.recv().await else {
         error!("init frame missing");
         return;
     };
 
-    let mut exit = false;
-    let mut opencv_frame = init_frame.get_opencv_frame();
-    opencv_frame.with_mut(|frame| {
-        if tracker.obj.init(frame.mat, init_box).is_err() {
-            error!("tracker setup failed");
-            exit = true;
-        }
-    });
-
-    if exit {
+    if !tracker.init(&mut init_frame, init_box) {
+        error!("tracker setup failed");
         return;
     }
 
+    // NOTE: the init frame has the frame number 0
+    let mut generation = 0;
+    let mut frame_number = 0;
+    let mut tracked_frame_number = 0;
+    let mut history: VecDeque<(u32, FFmpegFrame)> = VecDeque::with_capacity(history_size + 1);
     let mut bounding_box = init_box;
-    while let Some(mut frame) = consumer.recv().await {
-        let mut opencv_frame = frame.get_opencv_frame();
-        opencv_frame.with_mut(|frame| {
-            if tracker.obj.update(frame.mat, &mut bounding_box).is_err() {
-                error!("tracking lost");
-                exit = true;
+    let stats_label = format!("tracker update feature {}", feature + 1);
+    // forward boxes waiting for the forward-backward check
+    let mut pending: Vec<(u32, FFmpegFrame, opencv::core::Rect)> = vec![];
+
+    loop {
+        tokio::select! {
+            biased;
+
+            Some(reinit) = control.recv() => {
+                let mut frame = reinit.frame;
+                tracker = FeatureTracker::new(settings.kind);
+                if !tracker.init(&mut frame, reinit.init_box) {
+                    error!("tracker re-initialization failed");
+                    break;
+                }
+
+                generation = reinit.generation;
+                bounding_box = reinit.init_box;
+                tracked_frame_number = reinit.frame_number;
+                // the pending boxes belong to the previous generation
+                pending.clear();
+
+                if let Some((oldest, _)) = history.front() {
+                    if *oldest > reinit.frame_number + 1 && frame_number > reinit.frame_number {
+                        warn!("tracker history too short, skip frames before {oldest}");
+                    }
+                }
+
+                let mut exit = false;
+                for (number, frame) in history
+                    .iter_mut()
+                    .filter(|(number, _)| *number > reinit.frame_number)
+                {
+                    if !tracker.update(frame, &mut bounding_box) {
+                        error!("tracking lost");
+                        send_boxes(&mut pending, generation, settings, true, &producer).await;
+                        exit = true;
+                        break;
+                    }
+
+                    tracked_frame_number = *number;
+                    pending.push((*number, frame.clone(), bounding_box));
+                    if !send_boxes(&mut pending, generation, settings, false, &producer).await {
+                        exit = true;
+                        break;
+                    }
+                }
+
+                if exit || !send_boxes(&mut pending, generation, settings, true, &producer).await {
+                    break;
+                }
             }
-        });
 
-        if exit {
-            break;
+            frame = consumer.recv() => {
+                let Some(mut frame) = frame else {
+                    send_boxes(&mut pending, generation, settings, true, &producer).await;
+                    break;
+                };
+
+                frame_number += 1;
+                history.push_back((frame_number, frame.clone()));
+                if history.len() > history_size {
+                    history.pop_front();
+                }
+
+                // frames before a re-initialization frame we did not receive yet
+                if frame_number <= tracked_frame_number {
+                    continue;
+                }
+
+                let update_start = std::time::Instant::now();
+                if !tracker.update(&mut frame, &mut bounding_box) {
+                    error!("tracking lost");
+                    send_boxes(&mut pending, generation, settings, true, &producer).await;
+                    break;
+                }
+                stats::record(stats_label.as_str(), update_start);
+
+                tracked_frame_number = frame_number;
+                pending.push((frame_number, frame, bounding_box));
+                if !send_boxes(&mut pending, generation, settings, false, &producer).await {
+                    break;
+                }
+            }
         }
+    }
+}
+
+/// Send the pending boxes once the forward-backward window is complete or `flush` is set
+async fn send_boxes(
+    pending: &mut Vec<(u32, FFmpegFrame<'_>, opencv::core::Rect)>,
+    generation: u32,
+    settings: TrackerSettings,
+    flush: bool,
+    producer: &tokio::sync::mpsc::Sender<TrackingBox>,
+) -> bool {
+    let flags = match settings.fb_check {
+        Some(check) if pending.len() < check.window && !flush => return true,
+        Some(check) => {
+            let mut frames = pending
+                .iter()
+                .map(|(_, frame, _)| frame.clone())
+                .collect::<Vec<_>>();
+            let boxes = pending.iter().map(|(_, _, rect)| *rect).collect::<Vec<_>>();
+            let check_start = std::time::Instant::now();
+            let flags = backward_check(settings.kind, &mut frames, &boxes, check.threshold);
+            stats::record("forward-backward check", check_start);
+            flags
+        }
+        None => vec![false; pending.len()],
+    };
 
-        if producer.send(bounding_box).await.is_err() {
+    for ((frame_number, _, rect), flagged) in pending.drain(..).zip(flags) {
+        let tracking_box = TrackingBox {
+            generation,
+            frame_number,
+            rect,
+            flagged,
+        };
+        if producer.send(tracking_box).await.is_err() {
             error!("tracker: error adding box to process queue");
-            break;
+            return false;
         }
     }
+
+    true
 }
@@ -1,21 +1,83 @@
 use log::error;
+use log::warn;
+
+/// Minimum range of a window relative to the clipped scene range, so static parts of the
+/// scene do not amplify the tracking noise to full strokes
+const MIN_WINDOW_RANGE: f64 = 0.1;
+
+/// Minimum stroke height relative to the clipped scene range for the stroke normalization
+const STROKE_PROMINENCE: f64 = 0.1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Normalization {
+    /// One min/max for the whole scene
+    Global,
+    /// Min/max of a sliding window with `--normalize-window` length around each frame
+    Window,
+    /// Each stroke use the full output range
+    Stroke,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Detrend {
+    None,
+    /// Zero phase first order high-pass with a cutoff period of `--detrend-window`
+    HighPass,
+    /// Subtract the moving average over `--detrend-window`
+    Baseline,
+    /// Subtract a least squares polynomial of `--detrend-degree`
+    Polynomial,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TrackingEventKind {
+    Pause,
+    Resume,
+    Reselect(usize),
+    Cut,
+    Skip,
+    SceneCut,
+}
+
+/// User interaction during tracking, `frame` use the same numbering as `frames`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TrackingEvent {
+    pub frame: u32,
+    pub kind: TrackingEventKind,
+}
+
+impl TrackingEvent {
+    pub fn new(frame: u32, kind: TrackingEventKind) -> Self {
+        Self { frame, kind }
+    }
+}
 
 pub struct TrackingTrajectories {
     pub timestep: u32,
     pub trackers: usize,
+    /// Frame number (in steps of `timestep`) of each trajectories entry
+    pub frames: Vec<u32>,
     pub trajectories: Vec<Vec<mint::Point2<i32>>>,
+    pub events: Vec<TrackingEvent>,
+    /// Number of frames removed by `drop_frames`
+    pub dropped_frames: usize,
 }
 
 impl TrackingTrajectories {
     pub fn new(
         timestep: u32,
         trackers: usize,
+        frames: Vec<u32>,
         tracking_trajectories: Vec<Vec<opencv::core::Rect>>,
+        events: Vec<TrackingEvent>,
     ) -> Self {
         Self {
             timestep,
             trackers,
+            frames,
             trajectories: TrackingTrajectories::get_center_points(tracking_trajectories),
+            events,
+            dropped_frames: 0,
         }
     }
 
@@ -36,70 +98,529 @@ impl TrackingTrajectories {
         result
     }
 
+    /// Drop the frames flagged by the forward-backward check, the post-processing handle
+    /// them like skipped frames. The score of a frame needs the boxes of all features, so
+    /// the whole frame is dropped when the check fails for any of them. The dropped ranges
+    /// are logged as video time in milliseconds.
+    pub fn drop_frames(&mut self, flagged: &[u32], video_fps: f32, start_time_in_ms: f32) {
+        if flagged.is_empty() {
+            return;
+        }
+
+        let mut ranges: Vec<(u32, u32)> = vec![];
+        for frame in flagged.iter() {
+            match ranges.last_mut() {
+                Some(range) if range.1 + 1 == *frame => range.1 = *frame,
+                _ => ranges.push((*frame, *frame)),
+            }
+        }
+        let to_ms =
+            |frame: u32| start_time_in_ms + 1000.0 / video_fps * (frame * self.timestep) as f32;
+        let ranges = ranges
+            .iter()
+            .map(|(first, last)| {
+                if first == last {
+                    format!("{:.0} ms", to_ms(*first))
+                } else {
+                    format!("{:.0}-{:.0} ms", to_ms(*first), to_ms(*last))
+                }
+            })
+            .collect::<Vec<_>>();
+        warn!(
+            "forward-backward check failed for {} of {} frames at {}",
+            flagged.len(),
+            self.frames.len(),
+            ranges.join(", ")
+        );
+
+        let mut idx = 0;
+        self.trajectories.retain(|_| {
+            idx += 1;
+            flagged.binary_search(&self.frames[idx - 1]).is_err()
+        });
+        let frames = self.frames.len();
+        self.frames
+            .retain(|frame| flagged.binary_search(frame).is_err());
+        self.dropped_frames += frames - self.frames.len();
+    }
+
+    /// Score of a single frame like `get_y_diff` but without the offset of the single
+    /// tracker mode
+    pub fn get_frame_y_diff(boxes: &[opencv::core::Rect]) -> Option<i32> {
+        let center_y = boxes
+            .iter()
+            .map(|b| b.y + b.height / 2)
+            .collect::<Vec<i32>>();
+        match center_y.len() {
+            1 => Some(center_y[0]),
+            2 => Some(center_y[0] - center_y[1]),
+            _ => None,
+        }
+    }
+
     pub fn get_y_diff(&mut self) -> Vec<mint::Point2<i32>> {
         let mut result = vec![];
-        let min_y: i32 = *self
+        if self.trackers != 1 && self.trackers != 2 {
+            error!("invalid tracker number");
+            return result;
+        }
+
+        let min_y = self
             .trajectories
             .iter()
-            .map(|a| a.first().unwrap().y)
-            .collect::<Vec<i32>>()
-            .iter()
+            .filter_map(|a| a.first())
+            .map(|a| a.y)
             .min()
-            .unwrap();
-        for (idx, t) in self.trajectories.iter().enumerate() {
-            match self.trackers {
-                // NOTE: x has an offset of 1 frame because init frame box is not included in score
-                1 => {
-                    result.push(mint::Point2 {
-                        x: ((idx + 1) * (self.timestep as usize)) as i32,
-                        y: t.first().unwrap().y - min_y,
-                    });
-                }
-                2 => {
-                    result.push(mint::Point2 {
-                        x: ((idx + 1) * (self.timestep as usize)) as i32,
-                        y: t.get(0).unwrap().y - t.get(1).unwrap().y,
-                    });
-                }
+            .unwrap_or(0);
+        let mut missing = 0;
+        for (frame, t) in self.frames.iter().zip(self.trajectories.iter()) {
+            // NOTE: frame numbers start with 1 because init frame box is not included in score
+            let y = match (self.trackers, t.first(), t.get(1)) {
+                (1, Some(a), _) => a.y - min_y,
+                (2, Some(a), Some(b)) => a.y - b.y,
                 _ => {
-                    error!("invalid tracker number");
-                    break;
+                    missing += 1;
+                    continue;
                 }
-            }
+            };
+            result.push(mint::Point2 {
+                x: (frame * self.timestep) as i32,
+                y,
+            });
+        }
+
+        if missing > 0 {
+            warn!("skip {missing} frames with missing tracking boxes");
+        }
+
+        if result.is_empty() {
+            warn!("no tracking data");
         }
+
         result
     }
 
+    /// Scale the y values to `lower..=upper`, a constant signal is mapped to the middle
     pub fn scale_y(
         input: Vec<mint::Point2<i32>>,
         lower: Option<i32>,
         upper: Option<i32>,
-    ) -> Vec<mint::Point2<i32>> {
-        let min_y = *input
-            .iter()
-            .map(|a| a.y)
-            .collect::<Vec<i32>>()
-            .iter()
-            .min()
-            .unwrap();
-
-        let max_y = *input
-            .iter()
-            .map(|a| a.y)
-            .collect::<Vec<i32>>()
-            .iter()
-            .max()
-            .unwrap();
+    ) -> Vec<mint::Point2<f64>> {
+        let lower = lower.unwrap_or(0) as f64;
+        let upper = upper.unwrap_or(100) as f64;
+        let (Some(min_y), Some(max_y)) = (
+            input.iter().map(|a| a.y).min(),
+            input.iter().map(|a| a.y).max(),
+        ) else {
+            return vec![];
+        };
 
         input
             .iter()
             .map(|a| mint::Point2 {
-                x: a.x,
-                y: (((upper.unwrap_or(100) as f32) - (lower.unwrap_or(0) as f32))
-                    * ((a.y as f32) - (min_y as f32))
-                    / ((max_y as f32) - (min_y as f32))
-                    + (lower.unwrap_or(0) as f32)) as i32,
+                x: a.x as f64,
+                y: if max_y > min_y {
+                    (upper - lower) * ((a.y - min_y) as f64) / ((max_y - min_y) as f64) + lower
+                } else {
+                    (upper + lower) / 2.0
+                },
             })
             .collect()
     }
+
+    /// Remove slow position changes (e.g. camera movement) from the y values, `window` is the
+    /// length in frames of the slowest movement we keep. The result keeps sub pixel values.
+    pub fn detrend_y(
+        input: Vec<mint::Point2<i32>>,
+        method: Detrend,
+        window: u32,
+        degree: usize,
+    ) -> Vec<mint::Point2<f64>> {
+        let x = input.iter().map(|a| a.x as f64).collect::<Vec<_>>();
+        let y = input.iter().map(|a| a.y as f64).collect::<Vec<_>>();
+
+        let detrended = match method {
+            _ if input.len() < 2 => y,
+            Detrend::None => y,
+            Detrend::HighPass => {
+                let rc = window as f64 / (2.0 * std::f64::consts::PI);
+                let forward = high_pass(&x, &y, rc);
+                let mut backward = high_pass(
+                    &x.iter().rev().map(|v| -v).collect::<Vec<_>>(),
+                    &forward.into_iter().rev().collect::<Vec<_>>(),
+                    rc,
+                );
+                backward.reverse();
+                backward
+            }
+            Detrend::Baseline => {
+                let half_window = window as f64 / 2.0;
+                let mut start = 0;
+                let mut end = 0;
+                let mut sum = 0.0;
+                let mut result = Vec::with_capacity(y.len());
+                for (point, value) in x.iter().zip(y.iter()) {
+                    while end < x.len() && x[end] <= point + half_window {
+                        sum += y[end];
+                        end += 1;
+                    }
+                    while x[start] < point - half_window {
+                        sum -= y[start];
+                        start += 1;
+                    }
+                    result.push(value - sum / (end - start) as f64);
+                }
+                result
+            }
+            Detrend::Polynomial => match polynomial_fit(&x, &y, degree) {
+                Some(coefficients) => {
+                    let (offset, scale) = normalize_x(&x);
+                    x.iter()
+                        .zip(y.iter())
+                        .map(|(x, y)| {
+                            let t = (x - offset) * scale;
+                            y - coefficients.iter().rev().fold(0.0, |acc, c| acc * t + c)
+                        })
+                        .collect()
+                }
+                None => {
+                    warn!("polynomial detrend failed, keep the trajectories unchanged");
+                    y
+                }
+            },
+        };
+
+        x.into_iter()
+            .zip(detrended)
+            .map(|(x, y)| mint::Point2 { x, y })
+            .collect()
+    }
+
+    /// Like `scale_y` but with local min/max values. `window` is the sliding window length
+    /// in frames and `clip_percentile` the percentage of outliers ignored on each side.
+    pub fn normalize_y(
+        input: Vec<mint::Point2<f64>>,
+        method: Normalization,
+        window: u32,
+        clip_percentile: f64,
+        lower: Option<i32>,
+        upper: Option<i32>,
+    ) -> Vec<mint::Point2<f64>> {
+        let lower = lower.unwrap_or(0) as f64;
+        let upper = upper.unwrap_or(100) as f64;
+        let scale = |y: f64, (min_y, max_y): (f64, f64)| {
+            let value = if max_y - min_y > f64::EPSILON {
+                ((y - min_y) / (max_y - min_y)).clamp(0.0, 1.0)
+            } else {
+                0.5
+            };
+            (upper - lower) * value + lower
+        };
+
+        let values = input.iter().map(|a| a.y).collect::<Vec<_>>();
+        let global_range = percentile_range(&values, clip_percentile);
+        let min_range = MIN_WINDOW_RANGE * (global_range.1 - global_range.0);
+        if !input.is_empty() && global_range.1 <= global_range.0 {
+            warn!("constant tracking signal, no strokes detected");
+        }
+
+        let y = match method {
+            Normalization::Global => values.iter().map(|y| scale(*y, global_range)).collect(),
+            Normalization::Window => {
+                let half_window = (window / 2) as f64;
+                let mut start = 0;
+                let mut end = 0;
+                let mut result = Vec::with_capacity(values.len());
+                for (point, y) in input.iter().zip(values.iter()) {
+                    while input[start].x < point.x - half_window {
+                        start += 1;
+                    }
+                    while end < input.len() && input[end].x <= point.x + half_window {
+                        end += 1;
+                    }
+                    let (min_y, max_y) = percentile_range(&values[start..end], clip_percentile);
+                    // widen flat windows around their center
+                    let padding = (min_range - (max_y - min_y)).max(0.0) / 2.0;
+                    result.push(scale(*y, (min_y - padding, max_y + padding)));
+                }
+                result
+            }
+            Normalization::Stroke => {
+                let turning_points = crate::simplify::peaks(
+                    input.clone(),
+                    STROKE_PROMINENCE * (global_range.1 - global_range.0),
+                );
+
+                let mut result = Vec::with_capacity(values.len());
+                let mut stroke = turning_points.windows(2).peekable();
+                for (point, y) in input.iter().zip(values.iter()) {
+                    while let Some(s) = stroke.peek() {
+                        if point.x <= s[1].x {
+                            break;
+                        }
+                        stroke.next();
+                    }
+                    match stroke.peek() {
+                        Some(s) if (s[1].y - s[0].y).abs() >= min_range => {
+                            result.push(scale(*y, (s[0].y.min(s[1].y), s[0].y.max(s[1].y))))
+                        }
+                        _ => result.push(scale(*y, global_range)),
+                    }
+                }
+                result
+            }
+        };
+
+        input
+            .iter()
+            .zip(y)
+            .map(|(a, y)| mint::Point2 { x: a.x, y })
+            .collect()
+    }
+}
+
+/// First order RC high-pass for non uniform sampled values
+fn high_pass(x: &[f64], y: &[f64], rc: f64) -> Vec<f64> {
+    let mut result = Vec::with_capacity(y.len());
+    result.push(0.0);
+    for i in 1..y.len() {
+        let alpha = rc / (rc + (x[i] - x[i - 1]).abs());
+        result.push(alpha * (result[i - 1] + y[i] - y[i - 1]));
+    }
+    result
+}
+
+/// Offset and scale to map the x values to [-1, 1] for a well conditioned polynomial fit
+fn normalize_x(x: &[f64]) -> (f64, f64) {
+    let first = x[0];
+    let last = x[x.len() - 1];
+    let scale = if last > first {
+        2.0 / (last - first)
+    } else {
+        1.0
+    };
+    ((first + last) / 2.0, scale)
+}
+
+/// Least squares polynomial coefficients (lowest degree first) with normalized x values
+fn polynomial_fit(x: &[f64], y: &[f64], degree: usize) -> Option<Vec<f64>> {
+    let n = degree.min(x.len() - 1) + 1;
+    let (offset, scale) = normalize_x(x);
+
+    // normal equations as augmented matrix
+    let mut matrix = vec![vec![0.0; n + 1]; n];
+    for (x, y) in x.iter().zip(y.iter()) {
+        let t = (x - offset) * scale;
+        let powers = (0..n).map(|p| t.powi(p as i32)).collect::<Vec<_>>();
+        for row in 0..n {
+            for col in 0..n {
+                matrix[row][col] += powers[row] * powers[col];
+            }
+            matrix[row][n] += powers[row] * y;
+        }
+    }
+
+    // gaussian elimination with partial pivoting
+    for col in 0..n {
+        let pivot =
+            (col..n).max_by(|a, b| matrix[*a][col].abs().total_cmp(&matrix[*b][col].abs()))?;
+        if matrix[pivot][col].abs() < f64::EPSILON {
+            return None;
+        }
+        matrix.swap(col, pivot);
+        let pivot_row = matrix[col].clone();
+        for (idx, row) in matrix.iter_mut().enumerate() {
+            if idx != col {
+                let factor = row[col] / pivot_row[col];
+                for (value, pivot_value) in row.iter_mut().zip(pivot_row.iter()).skip(col) {
+                    *value -= factor * pivot_value;
+                }
+            }
+        }
+    }
+
+    Some((0..n).map(|i| matrix[i][n] / matrix[i][i]).collect())
+}
+
+/// Min and max value after ignoring `clip_percentile` percent of the values on each side
+fn percentile_range(values: &[f64], clip_percentile: f64) -> (f64, f64) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let offset =
+        ((clip_percentile.clamp(0.0, 50.0) / 100.0) * (sorted.len() - 1) as f64).round() as usize;
+    (sorted[offset], sorted[sorted.len() - 1 - offset])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DETRENDS: [Detrend; 4] = [
+        Detrend::None,
+        Detrend::HighPass,
+        Detrend::Baseline,
+        Detrend::Polynomial,
+    ];
+
+    const NORMALIZATIONS: [Normalization; 3] = [
+        Normalization::Global,
+        Normalization::Window,
+        Normalization::Stroke,
+    ];
+
+    fn point(x: i32, y: i32) -> mint::Point2<i32> {
+        mint::Point2 { x, y }
+    }
+
+    fn tracking(trackers: usize, boxes: Vec<Vec<mint::Point2<i32>>>) -> TrackingTrajectories {
+        TrackingTrajectories {
+            timestep: 2,
+            trackers,
+            frames: (1..=boxes.len() as u32).collect(),
+            trajectories: boxes,
+            events: vec![],
+            dropped_frames: 0,
+        }
+    }
+
+    #[test]
+    fn drop_flagged_frames() {
+        let mut result = tracking(2, vec![vec![point(0, 10), point(0, 30)]; 5]);
+        result.drop_frames(&[], 30.0, 0.0);
+        assert_eq!(result.dropped_frames, 0);
+
+        // frame 6 is not tracked and frame 2 is flagged by both features
+        result.drop_frames(&[2, 3, 5, 6], 30.0, 0.0);
+        assert_eq!(result.frames, [1, 4]);
+        assert_eq!(result.trajectories.len(), 2);
+        assert_eq!(result.dropped_frames, 3);
+    }
+
+    #[test]
+    fn y_diff_edge_cases() {
+        assert!(tracking(1, vec![]).get_y_diff().is_empty());
+        assert_eq!(
+            tracking(1, vec![vec![point(5, 40)]]).get_y_diff(),
+            vec![point(2, 0)]
+        );
+        assert_eq!(
+            tracking(1, vec![vec![point(5, 40)]; 3]).get_y_diff(),
+            vec![point(2, 0), point(4, 0), point(6, 0)]
+        );
+        assert!(tracking(3, vec![vec![point(5, 40)]; 3])
+            .get_y_diff()
+            .is_empty());
+    }
+
+    #[test]
+    fn y_diff_missing_boxes() {
+        let boxes = vec![
+            vec![point(0, 10), point(0, 30)],
+            vec![point(0, 12)],
+            vec![],
+            vec![point(0, 20), point(0, 25)],
+        ];
+        assert_eq!(
+            tracking(2, boxes).get_y_diff(),
+            vec![point(2, -20), point(8, -5)]
+        );
+    }
+
+    #[test]
+    fn scale_y_edge_cases() {
+        let scale = |input| TrackingTrajectories::scale_y(input, Some(0), Some(100));
+        assert!(scale(vec![]).is_empty());
+        assert_eq!(scale(vec![point(3, 7)])[0].y, 50.0);
+        assert!(scale(vec![point(1, 7); 4]).iter().all(|p| p.y == 50.0));
+
+        // gaps of missing frames keep their frame numbers
+        let scaled = scale(vec![point(0, 10), point(2, 20), point(10, 30)]);
+        assert_eq!(
+            scaled.iter().map(|p| p.x).collect::<Vec<_>>(),
+            [0.0, 2.0, 10.0]
+        );
+        assert_eq!(
+            scaled.iter().map(|p| p.y).collect::<Vec<_>>(),
+            [0.0, 50.0, 100.0]
+        );
+    }
+
+    #[test]
+    fn normalize_y_edge_cases() {
+        for method in NORMALIZATIONS {
+            let normalize = |input| {
+                TrackingTrajectories::normalize_y(input, method, 30, 0.0, Some(0), Some(100))
+            };
+            let sample = |x: f64, y: f64| mint::Point2 { x, y };
+
+            assert!(normalize(vec![]).is_empty(), "{method:?}");
+            assert_eq!(normalize(vec![sample(4.0, 3.0)])[0].y, 50.0, "{method:?}");
+            assert!(
+                normalize(vec![sample(0.0, 3.0); 5])
+                    .iter()
+                    .all(|p| p.y == 50.0),
+                "{method:?}"
+            );
+
+            let input = vec![
+                sample(0.0, 0.0),
+                sample(2.0, 10.0),
+                sample(40.0, 0.0),
+                sample(42.0, 10.0),
+            ];
+            let normalized = normalize(input.clone());
+            assert_eq!(normalized.len(), input.len(), "{method:?}");
+            for (p, q) in normalized.iter().zip(input.iter()) {
+                assert_eq!(p.x, q.x, "{method:?}");
+                assert!((0.0..=100.0).contains(&p.y), "{method:?}: {}", p.y);
+            }
+        }
+    }
+
+    #[test]
+    fn detrend_y_edge_cases() {
+        for method in DETRENDS {
+            let detrend = |input| TrackingTrajectories::detrend_y(input, method, 30, 1);
+
+            assert!(detrend(vec![]).is_empty(), "{method:?}");
+            let single = detrend(vec![point(4, 3)]);
+            assert_eq!(single.len(), 1, "{method:?}");
+            assert_eq!((single[0].x, single[0].y), (4.0, 3.0), "{method:?}");
+
+            let constant = detrend(vec![point(0, 7), point(2, 7), point(4, 7)]);
+            let expected = if method == Detrend::None { 7.0 } else { 0.0 };
+            assert!(
+                constant.iter().all(|p| (p.y - expected).abs() < 1e-9),
+                "{method:?}: {constant:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn detrend_y_missing_frames() {
+        // a linear drift sampled with gaps is removed completely by the polynomial detrend
+        let input = [0, 2, 4, 20, 22, 60]
+            .iter()
+            .map(|x| point(*x, 3 * x + 5))
+            .collect::<Vec<_>>();
+        let detrended = TrackingTrajectories::detrend_y(input, Detrend::Polynomial, 30, 1);
+        assert_eq!(
+            detrended.iter().map(|p| p.x).collect::<Vec<_>>(),
+            [0.0, 2.0, 4.0, 20.0, 22.0, 60.0]
+        );
+        assert!(detrended.iter().all(|p| p.y.abs() < 1e-6), "{detrended:?}");
+
+        for method in DETRENDS {
+            let detrended = TrackingTrajectories::detrend_y(
+                vec![point(0, 0), point(2, 10), point(50, 0), point(52, 10)],
+                method,
+                30,
+                2,
+            );
+            assert!(detrended.iter().all(|p| p.y.is_finite()), "{method:?}");
+        }
+    }
 }
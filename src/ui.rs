@@ -1,19 +1,84 @@
 use crate::ffmpeg;
 use crate::ffmpeg::FFmpegFrame;
+use crate::ffmpeg::FrameBuffer;
+use crate::projection::Reprojection;
 use image::DynamicImage;
 use log::error;
 use log::info;
+use once_cell::sync::Lazy;
+use std::sync::Arc;
+use std::sync::Mutex;
 
 const FONT_NAME: &str = "Hack";
 const FONT_SIZE: i32 = 18;
+const HERSHEY_FONT_SCALE: f64 = 0.6;
+const GRAPH_HEIGHT: i32 = 120;
+const GRAPH_MARGIN: i32 = 10;
 
+/// `add_text_with_font` is only available in OpenCV builds with Qt highgui
+static QT_HIGHGUI: Lazy<bool> = Lazy::new(|| {
+    let qt = opencv::core::get_build_information()
+        .map(|info| {
+            info.lines().any(|line| {
+                let line = line.trim();
+                line.starts_with("QT:") && line.contains("YES")
+            })
+        })
+        .unwrap_or(false);
+    if !qt {
+        info!("OpenCV without Qt support, use fallback text rendering");
+    }
+    qt
+});
+
+fn draw_text(mat: &mut opencv::core::Mat, text: &str, line: i32) {
+    let origin = opencv::core::Point::new(5, 30 + line * (FONT_SIZE + 8));
+    if *QT_HIGHGUI {
+        opencv::highgui::add_text_with_font(
+            mat,
+            text,
+            origin,
+            FONT_NAME,
+            FONT_SIZE,
+            opencv::core::Scalar::new(0f64, -1f64, -1f64, -1f64),
+            0, /* opencv::highgui::QtFontWeights::QT_FONT_NORMAL */
+            0, /* opencv::highgui::QtFontStyles::QT_STYLE_NORMAL */
+            0,
+        )
+        .unwrap();
+        return;
+    }
+
+    // draw an outline to keep the text readable on bright and dark frames
+    for (color, thickness) in [
+        (opencv::core::Scalar::new(0f64, 0f64, 0f64, 0f64), 4),
+        (opencv::core::Scalar::new(255f64, 255f64, 255f64, 0f64), 1),
+    ] {
+        opencv::imgproc::put_text(
+            mat,
+            text,
+            origin,
+            opencv::imgproc::FONT_HERSHEY_SIMPLEX,
+            HERSHEY_FONT_SCALE,
+            color,
+            thickness,
+            opencv::imgproc::LINE_AA,
+            false,
+        )
+        .unwrap();
+    }
+}
+
+/// Let the user select `boxes` ROIs. Proposals for all boxes can be accepted with `Enter` /
+/// `Space`, with any other key they stay visible as guide for the manual selection.
 pub async fn get_rois(
     boxes: usize,
     window_name: &str,
     frame: &mut FFmpegFrame<'_>,
+    proposals: &[opencv::core::Rect],
 ) -> Vec<opencv::core::Rect> {
     let mut input: Vec<opencv::core::Rect> = vec![];
-    let mut frame = frame.clone();
+    let mut frame = frame.deep_copy();
     let mut opencv_frame = frame.get_opencv_frame();
 
     opencv::highgui::named_window(
@@ -23,18 +88,32 @@ pub async fn get_rois(
     .unwrap();
 
     opencv_frame.with_mut(|frame| {
-        opencv::highgui::add_text_with_font(
-            frame.mat,
-            "Select Tracking Features",
-            opencv::core::Point::new(5, 30),
-            FONT_NAME,
-            FONT_SIZE,
-            opencv::core::Scalar::new(0f64, -1f64, -1f64, -1f64),
-            0, /* opencv::highgui::QtFontWeights::QT_FONT_NORMAL */
-            0, /* opencv::highgui::QtFontStyles::QT_STYLE_NORMAL */
-            0,
-        )
-        .unwrap();
+        if !proposals.is_empty() && proposals.len() == boxes {
+            for proposal in proposals {
+                opencv::imgproc::rectangle(
+                    &mut frame.mat,
+                    *proposal,
+                    opencv::core::Scalar::new(0f64, 255f64, 255f64, 0f64),
+                    1,
+                    8,
+                    0,
+                )
+                .unwrap();
+            }
+
+            let mut prompt = frame.mat.clone();
+            draw_text(&mut prompt, "Proposed Features", 0);
+            draw_text(&mut prompt, "Enter: accept, other key: select", 1);
+            opencv::highgui::imshow(window_name, &prompt).unwrap();
+            let key = opencv::highgui::wait_key(0).unwrap();
+            if key == KEY_ENTER || key == KEY_NEWLINE || key == ' ' as i32 {
+                info!("accept proposed ROIs");
+                input = proposals.to_vec();
+                return;
+            }
+        }
+
+        draw_text(frame.mat, "Select Tracking Features", 0);
         while input.len() < boxes {
             match opencv::highgui::select_roi_for_window(window_name, frame.mat, true, false) {
                 Ok(result) => {
@@ -63,75 +142,419 @@ pub async fn get_rois(
     input
 }
 
+const VIEWPORT_FINE_STEP: i32 = 1;
+const VIEWPORT_STEP: i32 = 5;
+const VIEWPORT_COARSE_STEP: i32 = 15;
+const KEY_BACKSPACE: i32 = 8;
+const KEY_NEWLINE: i32 = 10;
+const KEY_ENTER: i32 = 13;
+const KEY_ESCAPE: i32 = 27;
+const KEY_DELETE: i32 = 127;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Viewport {
+    pub pitch: i32,
+    pub yaw: i32,
+    pub roll: i32,
+    pub fov: i32,
+}
+
+impl Default for Viewport {
+    fn default() -> Self {
+        Self {
+            pitch: -25,
+            yaw: 0,
+            roll: 0,
+            fov: 90,
+        }
+    }
+}
+
+impl Viewport {
+    /// Limit all angles to the range supported by the v360 filter, yaw wraps around
+    pub fn clamped(self) -> Self {
+        Self {
+            pitch: self.pitch.clamp(-90, 90),
+            yaw: (self.yaw + 180).rem_euclid(360) - 180,
+            roll: self.roll.clamp(-180, 180),
+            fov: self.fov.clamp(10, 170),
+        }
+    }
+
+    pub fn apply_to_filter(&self, video_filter_template: &str) -> String {
+        video_filter_template
+            .replace("{fov}", format!("{}", self.fov).as_str())
+            .replace("{pitch}", format!("{}", self.pitch).as_str())
+            .replace("{yaw}", format!("{}", self.yaw).as_str())
+            .replace("{roll}", format!("{}", self.roll).as_str())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ViewportField {
+    Pitch,
+    Yaw,
+    Roll,
+    Fov,
+}
+
+impl ViewportField {
+    fn name(&self) -> &'static str {
+        match self {
+            ViewportField::Pitch => "pitch",
+            ViewportField::Yaw => "yaw",
+            ViewportField::Roll => "roll",
+            ViewportField::Fov => "fov",
+        }
+    }
+
+    fn set(&self, viewport: &mut Viewport, value: i32) {
+        match self {
+            ViewportField::Pitch => viewport.pitch = value,
+            ViewportField::Yaw => viewport.yaw = value,
+            ViewportField::Roll => viewport.roll = value,
+            ViewportField::Fov => viewport.fov = value,
+        }
+    }
+}
+
+/// Mouse input collected by the highgui callback until the viewport loop consumes it
+#[derive(Default)]
+struct ViewportMouseInput {
+    last_position: Option<(i32, i32)>,
+    drag_x: f64,
+    drag_y: f64,
+    wheel: i32,
+}
+
+impl ViewportMouseInput {
+    fn handle_event(&mut self, event: i32, x: i32, y: i32, flags: i32) {
+        match event {
+            opencv::highgui::EVENT_LBUTTONDOWN => self.last_position = Some((x, y)),
+            opencv::highgui::EVENT_LBUTTONUP => self.last_position = None,
+            opencv::highgui::EVENT_MOUSEMOVE => {
+                if flags & opencv::highgui::EVENT_FLAG_LBUTTON == 0 {
+                    self.last_position = None;
+                    return;
+                }
+                if let Some((last_x, last_y)) = self.last_position {
+                    let factor = match ViewportMouseInput::step(flags) {
+                        VIEWPORT_FINE_STEP => 0.2,
+                        VIEWPORT_COARSE_STEP => 3.0,
+                        _ => 1.0,
+                    };
+                    self.drag_x += (x - last_x) as f64 * factor;
+                    self.drag_y += (y - last_y) as f64 * factor;
+                }
+                self.last_position = Some((x, y));
+            }
+            opencv::highgui::EVENT_MOUSEWHEEL => {
+                let delta = opencv::highgui::get_mouse_wheel_delta(flags).unwrap_or(0);
+                self.wheel += delta.signum() * ViewportMouseInput::step(flags);
+            }
+            _ => {}
+        }
+    }
+
+    /// Ctrl selects fine steps, Shift coarse steps
+    fn step(flags: i32) -> i32 {
+        if flags & opencv::highgui::EVENT_FLAG_CTRLKEY != 0 {
+            VIEWPORT_FINE_STEP
+        } else if flags & opencv::highgui::EVENT_FLAG_SHIFTKEY != 0 {
+            VIEWPORT_COARSE_STEP
+        } else {
+            VIEWPORT_STEP
+        }
+    }
+
+    fn take_motion(&mut self) -> (f64, f64, i32) {
+        let motion = (self.drag_x, self.drag_y, self.wheel);
+        self.drag_x = 0.0;
+        self.drag_y = 0.0;
+        self.wheel = 0;
+        motion
+    }
+}
+
+/// Interactive viewport selection
+///
+/// Keys: `w`/`s` pitch, `a`/`d` yaw, `[`/`]` roll, `+`/`-` fov, uppercase (shift) for
+/// coarse steps, `f` toggles fine steps, `p`/`y`/`r`/`v` type an exact pitch/yaw/roll/fov
+/// value (confirm with enter, cancel with escape), `q`/space/enter accept the viewport.
+/// Mouse: drag to pan, wheel to zoom, hold ctrl for fine and shift for coarse steps.
 pub async fn get_vr_viewport(
     window_name: &str,
     frame: &DynamicImage,
     video_filter_template: String,
 ) -> String {
-    let mut pitch: i8 = -25;
-    let mut yaw: i8 = 0;
-    let mut fov: u8 = 90;
-    let mut video_filter: String;
-    let mut loop_counter: u64 = 0;
+    let mut viewport = Viewport::default();
+    let mut projected_viewport: Option<Viewport> = None;
+    let mut projection: Option<FFmpegFrame> = None;
+    let mut reprojection = Reprojection::default();
+    let mut text_input: Option<(ViewportField, String)> = None;
+    let mut fine_steps = false;
+    let mut pan = (0f64, 0f64);
+    let mut redraw = true;
+    let mut shown = false;
 
-    loop {
-        video_filter = video_filter_template
-            .replace("{fov}", format!("{fov}").as_str())
-            .replace("{pitch}", format!("{pitch}").as_str())
-            .replace("{yaw}", format!("{yaw}").as_str());
-        let mut projection = ffmpeg::transform_frame(frame, video_filter.as_str())
-            .await
-            .unwrap()
-            .unwrap();
-        loop_counter += 1;
-        projection.get_opencv_frame().with_mut(|frame| {
-            if loop_counter > 1 {
-                // TODO Bug: window_QT.cpp:150: error: (-27:Null pointer) NULL guiReceiver
-                //   (please create a window) in function 'cvAddText'\n"
-                // Workaround: add text after first imshow
-                opencv::highgui::add_text_with_font(
-                    frame.mat,
-                    "Select Viewport",
-                    opencv::core::Point::new(5, 30),
-                    FONT_NAME,
-                    FONT_SIZE,
-                    opencv::core::Scalar::new(0f64, -1f64, -1f64, -1f64),
-                    0, /* opencv::highgui::QtFontWeights::QT_FONT_NORMAL */
-                    0, /* opencv::highgui::QtFontStyles::QT_STYLE_NORMAL */
-                    0,
-                )
-                .unwrap();
+    opencv::highgui::named_window(
+        window_name,
+        opencv::highgui::WINDOW_AUTOSIZE | opencv::highgui::WINDOW_GUI_NORMAL,
+    )
+    .unwrap();
+
+    let mouse_input = Arc::new(Mutex::new(ViewportMouseInput::default()));
+    let callback_mouse_input = mouse_input.clone();
+    opencv::highgui::set_mouse_callback(
+        window_name,
+        Some(Box::new(move |event, x, y, flags| {
+            if let Ok(mut input) = callback_mouse_input.lock() {
+                input.handle_event(event, x, y, flags);
             }
+        })),
+    )
+    .unwrap();
 
-            opencv::highgui::imshow(window_name, frame.mat).unwrap()
-        });
-        let key = opencv::highgui::wait_key(1).unwrap();
-        if key > 0 {
-            match char::from_u32(key.try_into().unwrap()) {
-                Some('q') => break,
-                Some(' ') => break,
-                Some('\n') => break,
-                Some('w') => pitch += 5,
-                Some('s') => pitch -= 5,
-                Some('a') => yaw -= 5,
-                Some('d') => yaw += 5,
-                Some('+') => fov -= 5,
-                Some('-') => fov += 5,
-                _ => {}
+    loop {
+        if projected_viewport != Some(viewport) {
+            let video_filter = viewport.apply_to_filter(video_filter_template.as_str());
+            projection = match reprojection.transform_frame(frame, video_filter.as_str()) {
+                Some(frame_buffer) => Some(FFmpegFrame::new(frame_buffer)),
+                None => ffmpeg::transform_frame(frame, video_filter.as_str())
+                    .await
+                    .unwrap(),
+            };
+            projected_viewport = Some(viewport);
+            redraw = true;
+        }
+
+        if redraw {
+            let Some(projection) = projection.as_ref() else {
+                error!("Viewport projection failed");
+                break;
             };
+            // NOTE: draw on a copy to keep the cached projection free of text
+            let mut canvas = projection.deep_copy();
+            canvas.get_opencv_frame().with_mut(|frame| {
+                if shown || !*QT_HIGHGUI {
+                    // TODO Bug: window_QT.cpp:150: error: (-27:Null pointer) NULL guiReceiver
+                    //   (please create a window) in function 'cvAddText'\n"
+                    // Workaround: add text after first imshow
+                    draw_text(frame.mat, "Select Viewport", 0);
+                    draw_text(
+                        frame.mat,
+                        format!(
+                            "pitch: {} yaw: {} roll: {} fov: {}{}",
+                            viewport.pitch,
+                            viewport.yaw,
+                            viewport.roll,
+                            viewport.fov,
+                            if fine_steps { " (fine)" } else { "" }
+                        )
+                        .as_str(),
+                        1,
+                    );
+                    if let Some((field, value)) = text_input.as_ref() {
+                        draw_text(frame.mat, format!("{}: {value}_", field.name()).as_str(), 2);
+                    }
+                }
+
+                opencv::highgui::imshow(window_name, frame.mat).unwrap()
+            });
+            redraw = !shown;
+            shown = true;
+        }
+
+        let (drag_x, drag_y, wheel) = mouse_input.lock().unwrap().take_motion();
+        if drag_x != 0.0 || drag_y != 0.0 || wheel != 0 {
+            let degree_per_pixel = viewport.fov as f64
+                / projection
+                    .as_ref()
+                    .map(|p| p.image.width().max(1))
+                    .unwrap_or(1) as f64;
+            pan.0 += drag_x * degree_per_pixel;
+            pan.1 += drag_y * degree_per_pixel;
+            // drag the image: moving the mouse right looks to the left
+            viewport.yaw -= pan.0.trunc() as i32;
+            viewport.pitch += pan.1.trunc() as i32;
+            pan.0 = pan.0.fract();
+            pan.1 = pan.1.fract();
+            viewport.fov -= wheel;
+            viewport = viewport.clamped();
+        }
+
+        let key = opencv::highgui::wait_key(10).unwrap();
+        if key <= 0 {
+            continue;
+        }
+
+        redraw = true;
+
+        if let Some((field, mut value)) = text_input.take() {
+            match key {
+                KEY_ENTER | KEY_NEWLINE => match value.parse::<i32>() {
+                    Ok(val) => {
+                        field.set(&mut viewport, val);
+                        viewport = viewport.clamped();
+                    }
+                    Err(_) => error!("Invalid {} value '{value}'", field.name()),
+                },
+                KEY_ESCAPE => {}
+                KEY_BACKSPACE | KEY_DELETE => {
+                    value.pop();
+                    text_input = Some((field, value));
+                }
+                _ => {
+                    match u32::try_from(key).ok().and_then(char::from_u32) {
+                        Some(c) if c.is_ascii_digit() => value.push(c),
+                        Some('-') if value.is_empty() => value.push('-'),
+                        _ => {}
+                    }
+                    text_input = Some((field, value));
+                }
+            }
+            continue;
+        }
+
+        let step = if fine_steps {
+            VIEWPORT_FINE_STEP
+        } else {
+            VIEWPORT_STEP
+        };
+
+        match u32::try_from(key).ok().and_then(char::from_u32) {
+            Some('q') | Some(' ') | Some('\n') | Some('\r') => break,
+            Some('w') => viewport.pitch += step,
+            Some('s') => viewport.pitch -= step,
+            Some('a') => viewport.yaw -= step,
+            Some('d') => viewport.yaw += step,
+            Some('W') => viewport.pitch += VIEWPORT_COARSE_STEP,
+            Some('S') => viewport.pitch -= VIEWPORT_COARSE_STEP,
+            Some('A') => viewport.yaw -= VIEWPORT_COARSE_STEP,
+            Some('D') => viewport.yaw += VIEWPORT_COARSE_STEP,
+            Some('[') => viewport.roll -= step,
+            Some(']') => viewport.roll += step,
+            Some('{') => viewport.roll -= VIEWPORT_COARSE_STEP,
+            Some('}') => viewport.roll += VIEWPORT_COARSE_STEP,
+            Some('+') | Some('=') => viewport.fov -= step,
+            Some('-') => viewport.fov += step,
+            Some('f') => fine_steps = !fine_steps,
+            Some('p') => text_input = Some((ViewportField::Pitch, String::new())),
+            Some('y') => text_input = Some((ViewportField::Yaw, String::new())),
+            Some('r') => text_input = Some((ViewportField::Roll, String::new())),
+            Some('v') => text_input = Some((ViewportField::Fov, String::new())),
+            _ => {}
+        };
+
+        viewport = viewport.clamped();
+    }
+
+    opencv::highgui::set_mouse_callback(window_name, None).unwrap();
+
+    info!(
+        "Selected viewport: pitch={} yaw={} roll={} fov={}",
+        viewport.pitch, viewport.yaw, viewport.roll, viewport.fov
+    );
+
+    viewport.apply_to_filter(video_filter_template.as_str())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PreviewAction {
+    Continue,
+    Stop,
+    TogglePause,
+    Reselect(usize),
+    Cut,
+    Skip,
+    Rewind,
+    Forward,
+}
+
+/// Rolling plot of the score below the tracking preview, y values in the range 0 to 100
+#[derive(Copy, Clone, Default)]
+pub struct ScoreGraph<'a> {
+    pub score: &'a [mint::Point2<f64>],
+    pub actions: &'a [mint::Point2<f64>],
+}
+
+fn draw_score_graph(mat: &mut opencv::core::Mat, area: opencv::core::Rect, graph: &ScoreGraph) {
+    let (Some(first), Some(last)) = (graph.score.first(), graph.score.last()) else {
+        return;
+    };
+    let x_range = (last.x - first.x).max(1.0);
+    let to_point = |p: &mint::Point2<f64>| {
+        opencv::core::Point::new(
+            area.x
+                + GRAPH_MARGIN
+                + ((p.x - first.x) * (area.width - 2 * GRAPH_MARGIN) as f64 / x_range) as i32,
+            area.y + area.height
+                - GRAPH_MARGIN
+                - (p.y.clamp(0.0, 100.0) * (area.height - 2 * GRAPH_MARGIN) as f64 / 100.0) as i32,
+        )
+    };
+
+    for (series, color, thickness) in [
+        (graph.score, opencv::core::Scalar::new(160f64, 160f64, 160f64, 0f64), 1),
+        (graph.actions, opencv::core::Scalar::new(0f64, 200f64, 255f64, 0f64), 2),
+    ] {
+        for segment in series
+            .iter()
+            .filter(|p| p.x >= first.x)
+            .collect::<Vec<_>>()
+            .windows(2)
+        {
+            opencv::imgproc::line(
+                mat,
+                to_point(segment[0]),
+                to_point(segment[1]),
+                color,
+                thickness,
+                opencv::imgproc::LINE_AA,
+                0,
+            )
+            .unwrap();
         }
     }
 
-    video_filter
+    for action in graph.actions.iter().filter(|p| p.x >= first.x) {
+        opencv::imgproc::circle(
+            mat,
+            to_point(action),
+            3,
+            opencv::core::Scalar::new(0f64, 200f64, 255f64, 0f64),
+            -1,
+            opencv::imgproc::LINE_AA,
+            0,
+        )
+        .unwrap();
+    }
 }
 
+/// Show the tracking boxes and the score graph on a copy of `frame`
+///
+/// Keys: `q` stop, `p`/space pause or resume, `1`-`9` re-select the ROI of the feature,
+/// `c` mark a cut, `n` skip forward and while paused `b`/`,` rewind and `.` step forward.
 pub async fn preview_tracking_boxes(
     window_name: &str,
-    frame: &mut FFmpegFrame<'_>,
+    frame: &FFmpegFrame<'_>,
     boxes: &Vec<opencv::core::Rect>,
+    graph: ScoreGraph<'_>,
     text: &str,
-) -> bool {
+    paused: bool,
+) -> PreviewAction {
+    let source = frame.image.as_rgb8().unwrap();
+    let graph_area = opencv::core::Rect::new(
+        0,
+        source.height() as i32,
+        source.width() as i32,
+        GRAPH_HEIGHT,
+    );
+    let mut canvas = FrameBuffer::new(source.width(), source.height() + GRAPH_HEIGHT as u32);
+    image::imageops::replace(&mut canvas, source, 0, 0);
+    let mut frame = FFmpegFrame::new(canvas);
     let mut opencv_frame = frame.get_opencv_frame();
+    opencv_frame.with_mut(|frame| draw_score_graph(frame.mat, graph_area, &graph));
     for tracking_box in boxes {
         opencv_frame.with_mut(|frame| {
             opencv::imgproc::rectangle(
@@ -182,28 +605,24 @@ pub async fn preview_tracking_boxes(
 
     opencv_frame.with_mut(|frame| {
         if !text.is_empty() {
-            opencv::highgui::add_text_with_font(
-                frame.mat,
-                text,
-                opencv::core::Point::new(5, 30),
-                FONT_NAME,
-                FONT_SIZE,
-                opencv::core::Scalar::new(0f64, -1f64, -1f64, -1f64),
-                0, /* opencv::highgui::QtFontWeights::QT_FONT_NORMAL */
-                0, /* opencv::highgui::QtFontStyles::QT_STYLE_NORMAL */
-                0,
-            )
-            .unwrap();
+            draw_text(frame.mat, text, 0);
         }
     });
 
     opencv_frame.with_mut(|frame| opencv::highgui::imshow(window_name, frame.mat).unwrap());
 
-    let key = opencv::highgui::wait_key(1).unwrap();
-    if key == 'q' as i32 {
-        info!("stop requested by user");
-        true
-    } else {
-        false
+    let key = opencv::highgui::wait_key(if paused { 50 } else { 1 }).unwrap();
+    match u32::try_from(key).ok().and_then(char::from_u32) {
+        Some('q') => {
+            info!("stop requested by user");
+            PreviewAction::Stop
+        }
+        Some('p') | Some(' ') => PreviewAction::TogglePause,
+        Some('c') => PreviewAction::Cut,
+        Some('n') => PreviewAction::Skip,
+        Some('b') | Some(',') => PreviewAction::Rewind,
+        Some('.') => PreviewAction::Forward,
+        Some(c @ '1'..='9') => PreviewAction::Reselect(c as usize - '1' as usize),
+        _ => PreviewAction::Continue,
     }
 }
@@ -0,0 +1,427 @@
+//! End to end regression tests with synthetic videos from the ffmpeg lavfi sources. The
+//! tests require ffmpeg and ffprobe and are ignored by default, run them with
+//! `cargo test -- --ignored`.
+
+use std::f64::consts::PI;
+use std::path::Path;
+use std::process::Command;
+use tempdir::TempDir;
+
+const FPS: u32 = 30;
+const DURATION_IN_SECONDS: u32 = 6;
+const TRACKERS: [&str; 2] = ["csrt", "optical-flow"];
+const SIMPLIFIERS: [&str; 3] = ["rdp", "peaks", "visvalingam"];
+
+#[derive(Copy, Clone, Debug)]
+enum Layout {
+    /// One box moving up and down
+    Flat,
+    /// Two boxes moving in opposite directions
+    TwoPersons,
+    /// One box in the left eye of a half equirectangular side by side video
+    VrSbs,
+}
+
+impl Layout {
+    fn persons(&self) -> u32 {
+        match self {
+            Layout::TwoPersons => 2,
+            _ => 1,
+        }
+    }
+
+    fn filter(&self) -> &'static str {
+        match self {
+            Layout::Flat | Layout::TwoPersons => "scale=320:240",
+            Layout::VrSbs => {
+                "v360=input=he:in_stereo=sbs:pitch=0:yaw=0:roll=0:output=flat:d_fov=90:w=400:h=400"
+            }
+        }
+    }
+
+    /// ROIs in the filtered first frame
+    fn rois(&self) -> Vec<&'static str> {
+        match self {
+            Layout::Flat => vec!["60,100,40,40"],
+            Layout::TwoPersons => vec!["60,100,40,40", "220,100,40,40"],
+            Layout::VrSbs => vec!["150,150,100,100"],
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Motion {
+    /// 1 Hz sine with constant amplitude
+    Sinusoidal,
+    /// Tempo rising from 0.8 to 1.6 Hz with an amplitude changing between 60 and 100 %
+    Scripted,
+}
+
+impl Motion {
+    /// Start frequency in Hz and its change in Hz per second
+    fn tempo(&self) -> (f64, f64) {
+        match self {
+            Motion::Sinusoidal => (1.0, 0.0),
+            Motion::Scripted => (0.8, 0.8 / DURATION_IN_SECONDS as f64),
+        }
+    }
+
+    /// Relative amplitude at `t` seconds
+    fn amplitude(&self, t: f64) -> f64 {
+        match self {
+            Motion::Sinusoidal => 1.0,
+            Motion::Scripted => 0.8 - 0.2 * (2.0 * PI * t / 3.0).cos(),
+        }
+    }
+
+    /// ffmpeg expression of the vertical offset with `amplitude` pixel, same as `amplitude`
+    /// times the sine of the phase of `tempo`
+    fn expression(&self, amplitude: i32) -> String {
+        let (frequency, chirp) = self.tempo();
+        let relative = match self {
+            Motion::Sinusoidal => "1".to_string(),
+            Motion::Scripted => "(0.8-0.2*cos(2*PI*t/3))".to_string(),
+        };
+        format!(
+            "{amplitude}*{relative}*sin(2*PI*({frequency}*t+{}*t*t))",
+            chirp / 2.0
+        )
+    }
+
+    /// Turning points of the motion in seconds with their position, a higher box is a
+    /// higher position
+    fn turning_points(&self) -> Vec<(f64, f64)> {
+        let (frequency, chirp) = self.tempo();
+        // the phase reach PI/2 + k*PI at frequency*t + chirp/2*t^2 = 0.25 + k/2
+        (0..)
+            .map(|k| {
+                let cycles = 0.25 + k as f64 / 2.0;
+                let t = if chirp > 0.0 {
+                    ((frequency * frequency + 2.0 * chirp * cycles).sqrt() - frequency) / chirp
+                } else {
+                    cycles / frequency
+                };
+                // sin = +1 (lowest box) for even k and -1 (highest box) for odd k
+                let direction = if k % 2 == 0 { -1.0 } else { 1.0 };
+                (t, 50.0 + 50.0 * direction * self.amplitude(t))
+            })
+            .take_while(|(t, _)| *t < DURATION_IN_SECONDS as f64)
+            .collect()
+    }
+}
+
+fn require_ffmpeg() {
+    let available = ["ffmpeg", "ffprobe"].iter().all(|cmd| {
+        Command::new(cmd)
+            .arg("-version")
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false)
+    });
+    assert!(available, "the end to end tests require ffmpeg and ffprobe");
+}
+
+fn ffmpeg(args: &[&str]) {
+    let status = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .args(args)
+        .status()
+        .expect("ffmpeg failed to start");
+    assert!(status.success(), "ffmpeg {args:?} failed");
+}
+
+/// Static feature with a texture in both directions, the box trackers and the optical flow
+/// see only the motion of the box
+fn generate_feature(path: &Path, size: u32) {
+    let filter = format!("scale={size}:{size}:flags=neighbor");
+    ffmpeg(&[
+        "-f",
+        "lavfi",
+        "-i",
+        "life=s=10x10:ratio=0.5:seed=7",
+        "-vf",
+        filter.as_str(),
+        "-frames:v",
+        "1",
+        path.to_str().unwrap(),
+    ]);
+}
+
+fn generate_video(dir: &Path, layout: Layout, motion: Motion) -> std::path::PathBuf {
+    let (size, overlay) = match layout {
+        Layout::Flat => (
+            "320x240",
+            format!("[0][1]overlay=x=60:y='100+{}'", motion.expression(50)),
+        ),
+        Layout::TwoPersons => (
+            "320x240",
+            format!(
+                "[0][1]overlay=x=60:y='100+{}'[a];[a][2]overlay=x=220:y='100-{}'",
+                motion.expression(25),
+                motion.expression(25)
+            ),
+        ),
+        Layout::VrSbs => (
+            "1600x800",
+            format!("[0][1]overlay=x=360:y='360+{}'", motion.expression(40)),
+        ),
+    };
+    let box_size = match layout {
+        Layout::VrSbs => 80,
+        _ => 40,
+    };
+
+    let feature = dir.join("feature.png");
+    generate_feature(&feature, box_size);
+
+    let video = dir.join("video.mp4");
+    let background = format!("color=c=black:s={size}:r={FPS}:d={DURATION_IN_SECONDS}");
+    let fps = FPS.to_string();
+    let duration = DURATION_IN_SECONDS.to_string();
+    let mut args = vec!["-f", "lavfi", "-i", background.as_str()];
+    for _ in 0..layout.persons() {
+        args.extend([
+            "-loop",
+            "1",
+            "-framerate",
+            fps.as_str(),
+            "-t",
+            duration.as_str(),
+            "-i",
+            feature.to_str().unwrap(),
+        ]);
+    }
+    args.extend(["-filter_complex", overlay.as_str()]);
+    args.extend(["-c:v", "libx264", "-pix_fmt", "yuv420p"]);
+    args.push(video.to_str().unwrap());
+    ffmpeg(&args);
+
+    video
+}
+
+/// Funscript with the turning points of the known motion
+fn generate_reference(path: &Path, motion: Motion) {
+    let actions = motion
+        .turning_points()
+        .iter()
+        .map(|(t, pos)| serde_json::json!({ "at": (t * 1000.0).round(), "pos": pos.round() }))
+        .collect::<Vec<_>>();
+    std::fs::write(
+        path,
+        serde_json::json!({ "version": "1.0", "actions": actions }).to_string(),
+    )
+    .unwrap();
+}
+
+fn mtfg(dir: &Path, args: &[&str]) -> bool {
+    Command::new(env!("CARGO_BIN_EXE_mtfg-rs"))
+        .current_dir(dir)
+        .args(args)
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Generated video and reference script shared by all runs of a test
+struct Scene {
+    dir: TempDir,
+    layout: Layout,
+    motion: Motion,
+    video: std::path::PathBuf,
+    reference: std::path::PathBuf,
+}
+
+impl Scene {
+    fn new(layout: Layout, motion: Motion) -> Self {
+        require_ffmpeg();
+        let dir = TempDir::new("mtfg-rs").unwrap();
+        let video = generate_video(dir.path(), layout, motion);
+        let reference = dir.path().join("reference.funscript");
+        generate_reference(&reference, motion);
+        Self {
+            dir,
+            layout,
+            motion,
+            video,
+            reference,
+        }
+    }
+
+    /// Track the scene headless and check every evaluation metric against its tolerance
+    fn check(&self, tracker: &str, simplify: &str, extra_args: &[&str]) {
+        let name = format!("{tracker}-{simplify}{}", extra_args.join(""));
+        let context = format!("{:?} {:?} {name}", self.layout, self.motion);
+        let generated = self.dir.path().join(format!("{name}.funscript"));
+        let report = self.dir.path().join(format!("{name}.json"));
+
+        let persons = self.layout.persons().to_string();
+        let mut args = vec![
+            "--headless",
+            "-i",
+            self.video.to_str().unwrap(),
+            "-o",
+            generated.to_str().unwrap(),
+            "-s",
+            "0",
+            "--persons",
+            persons.as_str(),
+            "--filter",
+            self.layout.filter(),
+            "--tracker",
+            tracker,
+            "--epsilon",
+            "3.0",
+            "--simplify",
+            simplify,
+        ];
+        for roi in self.layout.rois() {
+            args.extend(["--roi", roi]);
+        }
+        args.extend(extra_args);
+        assert!(mtfg(self.dir.path(), &args), "{context}: tracking failed");
+
+        assert!(
+            mtfg(
+                self.dir.path(),
+                &[
+                    "evaluate",
+                    "-g",
+                    generated.to_str().unwrap(),
+                    "-r",
+                    self.reference.to_str().unwrap(),
+                    "--json",
+                    report.to_str().unwrap(),
+                ],
+            ),
+            "{context}: evaluation failed"
+        );
+
+        let report: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(report).unwrap()).unwrap();
+        let metric = |name: &str| report[name].as_f64().unwrap_or(f64::NAN);
+        let context = format!("{context}: {report}");
+        assert!(metric("missed_strokes") <= 2.0, "{context}");
+        assert!(metric("extra_strokes") <= 2.0, "{context}");
+        assert!(metric("timing_error_mean_abs") <= 100.0, "{context}");
+        assert!(metric("timing_error_rms") <= 150.0, "{context}");
+        assert!(metric("position_correlation") >= 0.9, "{context}");
+        assert!(metric("dtw_distance") <= 15.0, "{context}");
+        // the strokes of the sinusoidal motion have no amplitude variance to correlate
+        if let Motion::Scripted = self.motion {
+            assert!(metric("amplitude_correlation") >= 0.5, "{context}");
+        }
+    }
+}
+
+fn check_all_trackers_and_simplifiers(layout: Layout, motion: Motion) {
+    let scene = Scene::new(layout, motion);
+    for tracker in TRACKERS {
+        for simplify in SIMPLIFIERS {
+            scene.check(tracker, simplify, &[]);
+        }
+    }
+}
+
+#[test]
+#[ignore = "requires ffmpeg"]
+fn flat_sinusoidal_motion() {
+    check_all_trackers_and_simplifiers(Layout::Flat, Motion::Sinusoidal);
+}
+
+#[test]
+#[ignore = "requires ffmpeg"]
+fn flat_scripted_motion() {
+    check_all_trackers_and_simplifiers(Layout::Flat, Motion::Scripted);
+}
+
+#[test]
+#[ignore = "requires ffmpeg"]
+fn two_persons_opposite_motion() {
+    check_all_trackers_and_simplifiers(Layout::TwoPersons, Motion::Sinusoidal);
+}
+
+#[test]
+#[ignore = "requires ffmpeg"]
+fn two_persons_scripted_motion() {
+    check_all_trackers_and_simplifiers(Layout::TwoPersons, Motion::Scripted);
+}
+
+#[test]
+#[ignore = "requires ffmpeg"]
+fn vr_side_by_side_motion() {
+    check_all_trackers_and_simplifiers(Layout::VrSbs, Motion::Sinusoidal);
+}
+
+#[test]
+#[ignore = "requires ffmpeg"]
+fn vr_side_by_side_scripted_motion() {
+    check_all_trackers_and_simplifiers(Layout::VrSbs, Motion::Scripted);
+}
+
+#[test]
+#[ignore = "requires ffmpeg"]
+fn chunked_tracking() {
+    let scene = Scene::new(Layout::Flat, Motion::Scripted);
+    for tracker in TRACKERS {
+        scene.check(tracker, "rdp", &["--chunks", "3"]);
+    }
+}
+
+#[test]
+#[ignore = "requires ffmpeg"]
+fn forward_backward_check() {
+    let scene = Scene::new(Layout::Flat, Motion::Scripted);
+    for tracker in TRACKERS {
+        scene.check(tracker, "rdp", &["--fb-check"]);
+        scene.check(tracker, "rdp", &["--chunks", "3", "--fb-check"]);
+    }
+}
+
+/// At of every line of a raw-csv output
+fn raw_timestamps(path: &Path) -> Vec<i64> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split(',').next()?.parse().ok())
+        .collect()
+}
+
+#[test]
+#[ignore = "requires ffmpeg"]
+fn chunked_frame_numbers_match_sequential_tracking() {
+    let scene = Scene::new(Layout::Flat, Motion::Sinusoidal);
+    let mut timestamps = vec![];
+    for chunks in ["1", "3"] {
+        let output = scene.dir.path().join(format!("chunks-{chunks}.raw.csv"));
+        let mut args = vec![
+            "--headless",
+            "-i",
+            scene.video.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+            "-s",
+            "0",
+            "--filter",
+            scene.layout.filter(),
+            "--epsilon",
+            "3.0",
+            "--chunks",
+            chunks,
+        ];
+        for roi in scene.layout.rois() {
+            args.extend(["--roi", roi]);
+        }
+        assert!(
+            mtfg(scene.dir.path(), &args),
+            "tracking with {chunks} chunks failed"
+        );
+        timestamps.push(raw_timestamps(&output));
+    }
+
+    let (sequential, chunked) = (&timestamps[0], &timestamps[1]);
+    assert_eq!(sequential.first(), chunked.first());
+    assert!(chunked.iter().all(|at| sequential.contains(at)));
+    // the chunks end at the frame count from the video duration
+    assert!(sequential.len().abs_diff(chunked.len()) <= 2);
+}